@@ -1,7 +1,8 @@
-use rtherm_common::ChannelId;
+use rtherm_common::{ChannelId, Encoding};
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
     path::Path,
 };
 use tokio::fs;
@@ -13,16 +14,135 @@ pub enum ProviderKind {
     Dummy,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageType {
+    #[default]
+    Mem,
+    Fs,
+}
+
+/// What to do with points that exceed a limit.
+#[derive(Clone, Copy, PartialEq, Default, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop oldest points.
+    #[default]
+    DropOldest,
+    /// Merge oldest points into one per specified interval (in seconds).
+    ///
+    /// If this is not enough, then oldest points are dropped.
+    /// For `max_age` limit points are only merged, so that old history is kept with lower resolution.
+    Downsample(f64),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Limit<T> {
+    pub value: T,
+    #[serde(default)]
+    pub policy: OverflowPolicy,
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// Max number of points per channel
+    pub max_points: Option<Limit<usize>>,
+    /// Max age of points in seconds
+    pub max_age: Option<Limit<f64>>,
+    /// Max approximate size of all points in bytes
+    pub max_bytes: Option<Limit<usize>>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub struct StorageConfig {
+    #[serde(rename = "type")]
+    pub type_: StorageType,
+    /// Path to journal file
+    pub path: Option<String>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+}
+
+/// String that is hidden when config is logged.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
+/// Key to sign requests with HMAC.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningConfig {
+    pub key_id: String,
+    pub key: Secret,
+}
+
+/// Schedule of repeated attempts to send measurements after failure.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Delay before the first retry in seconds
+    pub initial_delay: f64,
+    /// Max delay between retries in seconds
+    pub max_delay: f64,
+    /// Delay multiplier after each failed attempt
+    pub factor: f64,
+    /// Max random deviation of delay relative to its value
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: 5.0,
+            max_delay: 600.0,
+            factor: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub prefix: String,
     pub server: String,
+    /// Token to authenticate on server
+    pub token: Option<Secret>,
+    /// Sign requests instead of or in addition to sending token
+    pub signing: Option<SigningConfig>,
     pub period: f64,
     pub providers: HashSet<ProviderKind>,
     pub name_map: HashMap<String, ChannelId>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Max approximate size of a single request body in bytes
+    #[serde(default = "Config::default_max_request_size")]
+    pub max_request_size: usize,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Encoding of request body
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Compress request body with gzip
+    #[serde(default)]
+    pub compress: bool,
 }
 
 impl Config {
+    fn default_max_request_size() -> usize {
+        256 * 1024
+    }
+
     pub async fn read<P: AsRef<Path>>(path: P) -> Result<Config, String> {
         let bytes = fs::read(path).await.map_err(|e| format!("{e}"))?;
         let text = String::from_utf8(bytes).map_err(|e| format!("{e}"))?;
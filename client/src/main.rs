@@ -2,23 +2,41 @@ mod config;
 #[cfg(feature = "dummy")]
 mod dummy;
 mod provider;
+mod retention;
 mod storage;
+mod upload;
 #[cfg(feature = "w1_therm")]
 mod w1_therm;
 
 use crate::config::Config;
-use config::ProviderKind;
+use config::{ProviderKind, StorageType};
+use flate2::{write::GzEncoder, Compression};
 use provider::{AnyProvider, Provider};
-use reqwest::Client;
-use rtherm_common::{merge_groups, ChannelId, ProvideRequest};
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    Client, RequestBuilder,
+};
+use retention::Retention;
+use rtherm_common::{
+    error::AnyError, merge_groups, signature, ChannelId, Measurements, ProvideRequest,
+};
 use std::{
     collections::{hash_map::Entry, HashMap},
-    env, mem,
+    env,
+    fmt::Write as _,
+    future::pending,
+    io::Write,
+    mem,
     ops::Deref,
-    time::Duration,
+    time::{Duration, SystemTime},
+};
+use storage::{split_until, FileStorage, MemStorage, Storage, StorageGuard};
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel as channel, UnboundedReceiver as Receiver},
+    time::{sleep, sleep_until, Instant},
 };
-use storage::{MemStorage, Storage, StorageGuard};
-use tokio::{sync::mpsc::unbounded_channel as channel, time::sleep};
+use upload::Backoff;
 
 #[tokio::main]
 async fn main() -> ! {
@@ -41,9 +59,7 @@ async fn main() -> ! {
         log::info!("Dummy provider created");
     }
 
-    let mut storage = MemStorage::default();
-
-    let (producer, mut consumer) = channel();
+    let (producer, consumer) = channel();
     tokio::spawn({
         log::info!("Measurement task started");
         let config = config.clone();
@@ -62,88 +78,208 @@ async fn main() -> ! {
         }
     });
 
+    let retention = Retention::new(config.storage.retention.clone());
+    match config.storage.type_ {
+        StorageType::Mem => run(config, consumer, MemStorage::new(retention)).await,
+        StorageType::Fs => {
+            let path = config
+                .storage
+                .path
+                .clone()
+                .expect(r#"Storage type is set to "fs" but path is not provided"#);
+            let storage = FileStorage::open(path, retention)
+                .await
+                .expect("Cannot open storage file");
+            log::info!("File storage opened");
+            run(config, consumer, storage).await
+        }
+    }
+}
+
+async fn run<S: Storage>(
+    config: Config,
+    mut consumer: Receiver<Measurements<String>>,
+    mut storage: S,
+) -> ! {
     let client = Client::new();
+    let mut backoff = Backoff::new(config.retry.clone());
+    // Time of the next attempt to send measurements if the last one failed
+    let mut retry_at: Option<Instant> = None;
+    // Measurements that cannot be put into storage
+    let mut unstored = Measurements::default();
     let mut meas_buffer = Vec::new();
     loop {
-        if consumer.recv_many(&mut meas_buffer, usize::MAX).await == 0 {
-            panic!("Producer is closed");
-        }
-        let raw_meas = merge_groups(mem::take(&mut meas_buffer));
-        log::debug!("Measured: {:?}", raw_meas);
-        let mut meas = HashMap::new();
-        if !config.prefix.is_empty() {
-            for (chan_id, values) in raw_meas {
-                let id = match ChannelId::try_from(format!(
-                    "{}{}",
-                    config.prefix,
-                    match config.name_map.get(&chan_id) {
-                        Some(name) => name.to_string(),
-                        None => str_to_id_lossy(&chan_id),
-                    }
-                )) {
-                    Ok(id) => id,
-                    Err(err) => {
-                        log::error!("Bad channel name: {}", err);
-                        continue;
-                    }
-                };
-                match meas.entry(id) {
-                    Entry::Vacant(e) => e.insert(values),
-                    Entry::Occupied(e) => {
-                        log::error!("Key collision: {}", e.key());
-                        continue;
-                    }
-                };
-            }
-        }
-
-        let stored = match storage.store(meas.clone()).await {
-            Ok(()) => true,
-            Err(e) => {
-                log::error!("Cannot store measurements: {e}");
-                false
+        let retry = async {
+            match retry_at {
+                Some(time) => sleep_until(time).await,
+                None => pending().await,
             }
         };
-        let guard = match storage.load().await {
-            Ok(guard) => {
-                if stored {
-                    meas.clear();
+        select! {
+            count = consumer.recv_many(&mut meas_buffer, usize::MAX) => {
+                if count == 0 {
+                    panic!("Producer is closed");
+                }
+                let raw_meas = merge_groups(mem::take(&mut meas_buffer));
+                log::debug!("Measured: {:?}", raw_meas);
+                let meas = map_channels(&config, raw_meas);
+                if let Err(e) = storage.store(meas.clone()).await {
+                    log::error!("Cannot store measurements: {e}");
+                    unstored = merge_groups([mem::take(&mut unstored), meas]);
+                }
+                if retry_at.is_some() {
+                    // Wait for scheduled retry
+                    continue;
                 }
-                Some(guard)
             }
-            Err(e) => {
-                log::error!("Cannot load from storage: {e}");
-                None
+            () = retry => {
+                log::info!("Retrying to send measurements");
             }
-        };
+        }
 
-        let request = ProvideRequest {
-            measurements: match &guard {
-                Some(guard) => merge_groups([guard.deref().clone(), meas]),
-                None => meas,
-            },
+        if send(&config, &client, &mut storage, &mut unstored).await {
+            if retry_at.take().is_some() {
+                log::info!("Connection to '{}' restored", config.server);
+            }
+            backoff.reset();
+        } else {
+            let delay = backoff.next_delay();
+            log::info!("Next attempt to send measurements in {:?}", delay);
+            retry_at = Some(Instant::now() + delay);
+        }
+    }
+}
+
+/// Send all stored and unstored measurements.
+///
+/// Returns `true` if all of them have been successfully sent.
+async fn send<S: Storage>(
+    config: &Config,
+    client: &Client,
+    storage: &mut S,
+    unstored: &mut Measurements,
+) -> bool {
+    let guard = match storage.load().await {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            log::error!("Cannot load from storage: {e}");
+            None
+        }
+    };
+
+    let backlog = match &guard {
+        Some(guard) => merge_groups([guard.deref().clone(), unstored.clone()]),
+        None => unstored.clone(),
+    };
+    let mut acked = None;
+    let mut done = true;
+    for chunk in upload::split_chunks(backlog, config.max_request_size, config.encoding) {
+        let request = ProvideRequest::new(chunk.measurements);
+        let builder = match build_request(client, config, &request) {
+            Ok(builder) => builder,
+            Err(err) => {
+                log::error!("Cannot encode request: {err}");
+                done = false;
+                break;
+            }
         };
-        match client
-            .post(format!("{}/provide", config.server))
-            .json(&request)
-            .send()
-            .await
-            .and_then(|res| res.error_for_status())
-        {
+        match builder.send().await.and_then(|res| res.error_for_status()) {
             Ok(_) => {
                 log::debug!("Measurements sent to '{}'", config.server);
-                if let Some(guard) = guard {
-                    if let Err(e) = guard.remove().await {
-                        log::error!("Cannot clean up storage: {e}");
-                    }
-                }
+                acked = Some(chunk.last_time);
             }
             Err(err) => {
                 log::error!("Error sending measurements: {err}");
-                continue;
+                done = false;
+                break;
             }
         }
     }
+    if let Some(time) = acked {
+        *unstored = split_until(mem::take(unstored), time).1;
+        if let Some(guard) = guard {
+            if let Err(e) = guard.remove_until(time).await {
+                log::error!("Cannot clean up storage: {e}");
+            }
+        }
+    }
+    done
+}
+
+fn build_request(
+    client: &Client,
+    config: &Config,
+    request: &ProvideRequest,
+) -> Result<RequestBuilder, AnyError> {
+    let mut body = request.encode(config.encoding).map_err(AnyError::new)?;
+    let mut builder = client
+        .post(format!("{}/provide", config.server))
+        .header(CONTENT_TYPE, config.encoding.content_type());
+    if let Some(token) = &config.token {
+        builder = builder.bearer_auth(token.expose());
+    }
+    if let Some(signing) = &config.signing {
+        // Signature is computed over uncompressed body
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let nonce = hex_nonce();
+        builder = builder
+            .header(signature::KEY_ID_HEADER, &signing.key_id)
+            .header(signature::TIMESTAMP_HEADER, timestamp)
+            .header(
+                signature::SIGNATURE_HEADER,
+                signature::sign(signing.key.expose().as_bytes(), timestamp, &nonce, &body),
+            )
+            .header(signature::NONCE_HEADER, nonce);
+    }
+    if config.compress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).map_err(AnyError::new)?;
+        body = encoder.finish().map_err(AnyError::new)?;
+        builder = builder.header(CONTENT_ENCODING, "gzip");
+    }
+    Ok(builder.body(body))
+}
+
+fn hex_nonce() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .fold(String::new(), |mut accum, b| {
+            write!(&mut accum, "{b:02x}").unwrap();
+            accum
+        })
+}
+
+fn map_channels(config: &Config, raw_meas: Measurements<String>) -> Measurements {
+    let mut meas = HashMap::new();
+    if !config.prefix.is_empty() {
+        for (chan_id, values) in raw_meas {
+            let id = match ChannelId::try_from(format!(
+                "{}{}",
+                config.prefix,
+                match config.name_map.get(&chan_id) {
+                    Some(name) => name.to_string(),
+                    None => str_to_id_lossy(&chan_id),
+                }
+            )) {
+                Ok(id) => id,
+                Err(err) => {
+                    log::error!("Bad channel name: {}", err);
+                    continue;
+                }
+            };
+            match meas.entry(id) {
+                Entry::Vacant(e) => e.insert(values),
+                Entry::Occupied(e) => {
+                    log::error!("Key collision: {}", e.key());
+                    continue;
+                }
+            };
+        }
+    }
+    meas
 }
 
 fn str_to_id_lossy(s: &str) -> String {
@@ -0,0 +1,235 @@
+use crate::config::{Limit, OverflowPolicy, RetentionConfig};
+use rtherm_common::{Measurements, Point};
+use std::{
+    mem::size_of,
+    time::{Duration, SystemTime},
+};
+
+/// Keeps stored measurements within configured limits.
+#[derive(Clone, Default, Debug)]
+pub struct Retention {
+    config: RetentionConfig,
+    /// Total number of points lost due to limits
+    dropped: u64,
+}
+
+impl Retention {
+    /// Approximate size of single point
+    const POINT_SIZE: usize = size_of::<Point>();
+
+    pub fn new(config: RetentionConfig) -> Self {
+        Self { config, dropped: 0 }
+    }
+
+    /// Approximate size of measurements in bytes.
+    pub fn size(meas: &Measurements) -> usize {
+        meas.iter()
+            .map(|(id, points)| id.len() + points.len() * Self::POINT_SIZE)
+            .sum()
+    }
+
+    /// Drop or downsample points that exceed limits.
+    ///
+    /// Also sorts points of each channel by time.
+    pub fn apply(&mut self, meas: &mut Measurements) {
+        for points in meas.values_mut() {
+            points.sort_by_key(|p| p.time);
+        }
+
+        if let Some(limit) = self.config.max_age {
+            let now = SystemTime::now();
+            let max_age = Duration::from_secs_f64(limit.value);
+            let mut removed = 0;
+            for points in meas.values_mut() {
+                let old = points
+                    .partition_point(|p| now.duration_since(p.time).unwrap_or_default() > max_age);
+                removed += shrink(points, old, old, limit.policy);
+            }
+            self.report("max_age", removed);
+        }
+
+        if let Some(limit) = self.config.max_points {
+            let mut removed = 0;
+            for points in meas.values_mut() {
+                let excess = points.len().saturating_sub(limit.value);
+                if excess > 0 {
+                    let n = shrink(points, points.len(), excess, limit.policy);
+                    removed += n + drop_oldest(points, excess - n);
+                }
+            }
+            self.report("max_points", removed);
+        }
+
+        if let Some(Limit { value, policy }) = self.config.max_bytes {
+            let excess = Self::size(meas).saturating_sub(value);
+            let mut excess_points = excess.div_ceil(Self::POINT_SIZE);
+            let mut removed = 0;
+            if excess_points > 0 {
+                if let OverflowPolicy::Downsample(_) = policy {
+                    for points in meas.values_mut() {
+                        let n = shrink(points, points.len(), excess_points, policy);
+                        removed += n;
+                        excess_points -= n;
+                    }
+                }
+                // Drop globally oldest points until enough space is freed.
+                while excess_points > 0 {
+                    let oldest = meas
+                        .values_mut()
+                        .filter(|points| !points.is_empty())
+                        .min_by_key(|points| points[0].time);
+                    match oldest {
+                        Some(points) => {
+                            points.remove(0);
+                            removed += 1;
+                            excess_points -= 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            self.report("max_bytes", removed);
+        }
+
+        meas.retain(|_, points| !points.is_empty());
+    }
+
+    fn report(&mut self, limit: &str, removed: usize) {
+        if removed > 0 {
+            self.dropped += removed as u64;
+            log::warn!(
+                "{} points dropped due to {} limit ({} dropped in total)",
+                removed,
+                limit,
+                self.dropped
+            );
+        }
+    }
+}
+
+/// Remove up to `count` points from first `eligible` ones according to `policy`.
+///
+/// Returns number of removed points.
+fn shrink(points: &mut Vec<Point>, eligible: usize, count: usize, policy: OverflowPolicy) -> usize {
+    match policy {
+        OverflowPolicy::DropOldest => drop_oldest(points, eligible.min(count)),
+        OverflowPolicy::Downsample(interval) => {
+            downsample(points, eligible, count, Duration::from_secs_f64(interval))
+        }
+    }
+}
+
+fn drop_oldest(points: &mut Vec<Point>, count: usize) -> usize {
+    let count = count.min(points.len());
+    points.drain(..count);
+    count
+}
+
+/// Merge first `eligible` points into one point per `interval` starting from the oldest ones
+/// until `count` points are removed.
+///
+/// Merged point has the time of the first point and the mean value.
+fn downsample(points: &mut Vec<Point>, eligible: usize, count: usize, interval: Duration) -> usize {
+    let bucket = |p: &Point| {
+        p.time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            .div_euclid(interval.as_secs_f64().max(f64::MIN_POSITIVE)) as u64
+    };
+
+    let mut result = Vec::with_capacity(points.len());
+    let mut removed = 0;
+    let mut iter = points.drain(..).enumerate().peekable();
+    while let Some((i, first)) = iter.next() {
+        if i >= eligible || removed >= count {
+            result.push(first);
+            continue;
+        }
+        let (mut sum, mut n) = (first.value, 1);
+        while let Some((j, next)) = iter.peek() {
+            if *j >= eligible || removed >= count || bucket(next) != bucket(&first) {
+                break;
+            }
+            sum += next.value;
+            n += 1;
+            removed += 1;
+            iter.next();
+        }
+        result.push(Point {
+            value: sum / n as f64,
+            time: first.time,
+        });
+    }
+    drop(iter);
+    *points = result;
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtherm_common::ChannelId;
+    use std::time::UNIX_EPOCH;
+
+    fn channel(id: &str) -> ChannelId {
+        ChannelId::try_from(id).unwrap()
+    }
+
+    fn points(times: impl IntoIterator<Item = u64>) -> Vec<Point> {
+        times
+            .into_iter()
+            .map(|secs| Point {
+                value: secs as f64,
+                time: UNIX_EPOCH + Duration::from_secs(secs),
+            })
+            .collect()
+    }
+
+    fn times(points: &[Point]) -> Vec<u64> {
+        points
+            .iter()
+            .map(|p| p.time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn max_bytes_drops_globally_oldest() {
+        let mut meas = Measurements::from_iter([
+            (channel("a"), points([10, 20, 30])),
+            (channel("b"), points([15, 25, 35])),
+        ]);
+        let limit = Retention::size(&meas) - 3 * Retention::POINT_SIZE;
+        let mut retention = Retention::new(RetentionConfig {
+            max_bytes: Some(Limit {
+                value: limit,
+                policy: OverflowPolicy::DropOldest,
+            }),
+            ..Default::default()
+        });
+        retention.apply(&mut meas);
+        assert_eq!(times(&meas[&channel("a")]), [30]);
+        assert_eq!(times(&meas[&channel("b")]), [25, 35]);
+    }
+
+    #[test]
+    fn max_age_downsample_keeps_merged_points() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // Aged points are within a single downsampling interval.
+        let old = (now - 1000) / 10 * 10;
+        let mut meas =
+            Measurements::from_iter([(channel("a"), points([old, old + 1, old + 2, now]))]);
+        let mut retention = Retention::new(RetentionConfig {
+            max_age: Some(Limit {
+                value: 100.0,
+                policy: OverflowPolicy::Downsample(10.0),
+            }),
+            ..Default::default()
+        });
+        retention.apply(&mut meas);
+        assert_eq!(times(&meas[&channel("a")]), [old, now]);
+    }
+}
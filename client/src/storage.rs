@@ -1,5 +1,18 @@
-use rtherm_common::{merge_groups, Measurements};
-use std::{convert::Infallible, error::Error, future::Future, mem, ops::Deref};
+use crate::retention::Retention;
+use rtherm_common::{merge_groups, Measurements, ProvideRequest};
+use std::{
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    io, mem,
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
 
 pub trait Storage: Send {
     type Error: Error + Send;
@@ -18,13 +31,42 @@ pub trait Storage: Send {
 pub trait StorageGuard: Deref<Target = Measurements> + Send {
     type Error: Error + Send;
 
-    /// Remove data from storage
-    fn remove(self) -> impl Future<Output = Result<Measurements, Self::Error>> + Send;
+    /// Remove points with time not later than `time` from storage
+    fn remove_until(
+        self,
+        time: SystemTime,
+    ) -> impl Future<Output = Result<Measurements, Self::Error>> + Send;
+}
+
+/// Split measurements into points with time not later than `time` and the rest.
+pub fn split_until(meas: Measurements, time: SystemTime) -> (Measurements, Measurements) {
+    let mut before = Measurements::default();
+    let mut after = Measurements::default();
+    for (id, points) in meas {
+        let (old, new): (Vec<_>, Vec<_>) = points.into_iter().partition(|p| p.time <= time);
+        if !old.is_empty() {
+            before.insert(id.clone(), old);
+        }
+        if !new.is_empty() {
+            after.insert(id, new);
+        }
+    }
+    (before, after)
 }
 
 #[derive(Clone, Default, Debug)]
 pub struct MemStorage {
     measurements: Measurements,
+    retention: Retention,
+}
+
+impl MemStorage {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            measurements: Measurements::default(),
+            retention,
+        }
+    }
 }
 
 impl Storage for MemStorage {
@@ -33,6 +75,7 @@ impl Storage for MemStorage {
 
     async fn store(&mut self, meas: Measurements) -> Result<(), Self::Error> {
         self.measurements = merge_groups([mem::take(&mut self.measurements), meas]);
+        self.retention.apply(&mut self.measurements);
         Ok(())
     }
 
@@ -56,7 +99,416 @@ impl Deref for MemStorageGuard<'_> {
 impl<'a> StorageGuard for MemStorageGuard<'a> {
     type Error = Infallible;
 
-    async fn remove(self) -> Result<Measurements, Self::Error> {
-        Ok(mem::take(&mut self.storage.measurements))
+    async fn remove_until(self, time: SystemTime) -> Result<Measurements, Self::Error> {
+        let (removed, rest) = split_until(mem::take(&mut self.storage.measurements), time);
+        self.storage.measurements = rest;
+        Ok(removed)
+    }
+}
+
+/// Persistent storage backed by an append-only journal file.
+///
+/// Each [`Storage::store`] call appends a single record and syncs it to disk,
+/// so a power loss can only damage the record being written at that moment.
+/// Damaged tail is detected by checksum and discarded on the next open.
+///
+/// Record layout: `len: u32 LE | crc32: u32 LE | payload: [u8; len]`,
+/// where payload is JSON-encoded [`ProvideRequest`] without batch id.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    /// Length of valid records in journal file
+    len: u64,
+    /// Journal file may contain a torn record, so it must be rewritten before appending
+    torn: bool,
+    /// Number of records in journal
+    records: usize,
+    /// Cached contents of the journal
+    measurements: Measurements,
+    retention: Retention,
+}
+
+impl FileStorage {
+    const HEADER_LEN: usize = 8;
+    /// Journal is rewritten as a single record when number of records exceeds this value.
+    const MAX_RECORDS: usize = 64;
+
+    /// Open journal at `path` or create a new one if it does not exist.
+    ///
+    /// Points exceeding `retention` limits are removed from journal on compaction.
+    pub async fn open(
+        path: impl Into<PathBuf>,
+        mut retention: Retention,
+    ) -> Result<Self, io::Error> {
+        let path = path.into();
+        // Leftover from interrupted compaction or removal, journal itself is still valid.
+        remove_if_exists(&tmp_path(&path)).await?;
+
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let (groups, valid_len) = Self::decode(&data);
+        let groups_len = groups.len();
+        if valid_len < data.len() {
+            log::warn!(
+                "Journal {:?} has {} bytes of damaged tail, discarding them",
+                path,
+                data.len() - valid_len
+            );
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.set_len(valid_len as u64).await?;
+        file.sync_all().await?;
+        sync_parent(&path).await?;
+
+        let mut measurements = merge_groups(groups);
+        retention.apply(&mut measurements);
+        Ok(Self {
+            path,
+            file,
+            len: valid_len as u64,
+            torn: false,
+            records: groups_len,
+            measurements,
+            retention,
+        })
+    }
+
+    /// Decode records from journal data.
+    ///
+    /// Returns decoded records and length of their valid part.
+    fn decode(mut data: &[u8]) -> (Vec<Measurements>, usize) {
+        let mut groups = Vec::new();
+        let mut valid_len = 0;
+        while data.len() >= Self::HEADER_LEN {
+            let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+            let payload = match data[Self::HEADER_LEN..].get(..len) {
+                Some(payload) => payload,
+                None => break,
+            };
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+            match serde_json::from_slice::<ProvideRequest>(payload)
+                .map(|request| request.measurements)
+            {
+                Ok(meas) => groups.push(meas),
+                Err(e) => {
+                    log::error!("Cannot decode journal record: {e}");
+                    break;
+                }
+            }
+            data = &data[(Self::HEADER_LEN + len)..];
+            valid_len += Self::HEADER_LEN + len;
+        }
+        (groups, valid_len)
+    }
+
+    fn encode(meas: &Measurements) -> Result<Vec<u8>, io::Error> {
+        let payload = serde_json::to_vec(&ProvideRequest {
+            measurements: meas.clone(),
+            batch_id: None,
+        })?;
+        let len = u32::try_from(payload.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut record = Vec::with_capacity(Self::HEADER_LEN + payload.len());
+        record.extend(len.to_le_bytes());
+        record.extend(crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        Ok(record)
+    }
+
+    /// Atomically replace journal with a new one containing `meas`.
+    async fn rewrite(&mut self, meas: &Measurements) -> Result<(), io::Error> {
+        let tmp = tmp_path(&self.path);
+        {
+            let mut file = File::create(&tmp).await?;
+            if !meas.is_empty() {
+                file.write_all(&Self::encode(meas)?).await?;
+            }
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+        // Until the new journal is reopened `self.file` may refer to the replaced one.
+        self.torn = true;
+        fs::rename(&tmp, &self.path).await?;
+        sync_parent(&self.path).await?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        self.len = fs::metadata(&self.path).await?.len();
+        self.torn = false;
+        self.records = if meas.is_empty() { 0 } else { 1 };
+        Ok(())
+    }
+
+    /// Append record to journal.
+    ///
+    /// On failure partially written record is truncated,
+    /// otherwise records appended after it would be lost on the next open.
+    async fn append(&mut self, record: &[u8]) -> Result<(), io::Error> {
+        // Error of the background write is reported by flush, not by sync.
+        let result = match self.file.write_all(record).await {
+            Ok(()) => match self.file.flush().await {
+                Ok(()) => self.file.sync_data().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if let Err(e) = self.file.set_len(self.len).await {
+                log::error!("Cannot truncate journal after failed write: {e}");
+                self.torn = true;
+            }
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    type Error = io::Error;
+    type Guard<'a> = FileStorageGuard<'a>;
+
+    async fn store(&mut self, meas: Measurements) -> Result<(), Self::Error> {
+        if meas.is_empty() {
+            return Ok(());
+        }
+        if self.torn {
+            let mut merged = merge_groups([self.measurements.clone(), meas]);
+            self.retention.apply(&mut merged);
+            self.rewrite(&merged).await?;
+            self.measurements = merged;
+            return Ok(());
+        }
+        let record = Self::encode(&meas)?;
+        self.append(&record).await?;
+        self.measurements = merge_groups([mem::take(&mut self.measurements), meas]);
+        self.retention.apply(&mut self.measurements);
+
+        if self.records > Self::MAX_RECORDS {
+            let meas = mem::take(&mut self.measurements);
+            let result = self.rewrite(&meas).await;
+            self.measurements = meas;
+            if let Err(e) = result {
+                log::error!("Journal compaction failed: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn load(&mut self) -> Result<Self::Guard<'_>, Self::Error> {
+        Ok(FileStorageGuard { storage: self })
+    }
+}
+
+#[derive(Debug)]
+pub struct FileStorageGuard<'a> {
+    storage: &'a mut FileStorage,
+}
+
+impl Deref for FileStorageGuard<'_> {
+    type Target = Measurements;
+    fn deref(&self) -> &Self::Target {
+        &self.storage.measurements
+    }
+}
+
+impl<'a> StorageGuard for FileStorageGuard<'a> {
+    type Error = io::Error;
+
+    async fn remove_until(self, time: SystemTime) -> Result<Measurements, Self::Error> {
+        let (removed, rest) = split_until(self.storage.measurements.clone(), time);
+        self.storage.rewrite(&rest).await?;
+        self.storage.measurements = rest;
+        Ok(removed)
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Make sure that rename or creation of the file is persisted.
+async fn sync_parent(path: &Path) -> Result<(), io::Error> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir).await?.sync_all().await,
+        _ => File::open(".").await?.sync_all().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtherm_common::{ChannelId, Point};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn channel(id: &str) -> ChannelId {
+        ChannelId::try_from(id).unwrap()
+    }
+
+    fn meas(times: impl IntoIterator<Item = u64>) -> Measurements {
+        let points = times
+            .into_iter()
+            .map(|secs| Point {
+                value: secs as f64,
+                time: UNIX_EPOCH + Duration::from_secs(secs),
+            })
+            .collect();
+        Measurements::from_iter([(channel("a"), points)])
+    }
+
+    fn times(meas: &Measurements) -> Vec<u64> {
+        meas.get(&channel("a"))
+            .into_iter()
+            .flatten()
+            .map(|p| p.time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+            .collect()
+    }
+
+    async fn journal(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rtherm-storage-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join(name);
+        remove_if_exists(&path).await.unwrap();
+        path
+    }
+
+    #[test]
+    fn decode_stops_at_damaged_record() {
+        let first = FileStorage::encode(&meas([1])).unwrap();
+        let second = FileStorage::encode(&meas([2])).unwrap();
+
+        let torn = [&first[..], &second[..second.len() - 1]].concat();
+        let (groups, len) = FileStorage::decode(&torn);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(len, first.len());
+
+        let mut corrupted = [&first[..], &second[..]].concat();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        let (groups, len) = FileStorage::decode(&corrupted);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(len, first.len());
+    }
+
+    #[tokio::test]
+    async fn open_discards_torn_tail() {
+        let path = journal("torn").await;
+        let mut storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        storage.store(meas([1])).await.unwrap();
+        storage.store(meas([2])).await.unwrap();
+        let valid_len = storage.len;
+        drop(storage);
+
+        let record = FileStorage::encode(&meas([3])).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(&record[..record.len() / 2]).await.unwrap();
+        drop(file);
+
+        let mut storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        assert_eq!(times(&storage.measurements), [1, 2]);
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), valid_len);
+
+        // Records appended after recovery are not hidden behind the damaged one.
+        storage.store(meas([4])).await.unwrap();
+        drop(storage);
+        let storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        assert_eq!(times(&storage.measurements), [1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn failed_append_is_discarded() {
+        let path = journal("failed").await;
+        let mut storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        storage.store(meas([1])).await.unwrap();
+        let valid_len = storage.len;
+
+        // Neither write nor truncation is possible through read-only handle.
+        storage.file = File::open(&path).await.unwrap();
+        assert!(storage.store(meas([2])).await.is_err());
+        assert!(storage.torn);
+        assert_eq!(storage.len, valid_len);
+        assert_eq!(times(&storage.measurements), [1]);
+
+        storage.store(meas([3])).await.unwrap();
+        assert!(!storage.torn);
+        drop(storage);
+        let storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        assert_eq!(times(&storage.measurements), [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn journal_is_compacted() {
+        let path = journal("compacted").await;
+        let mut storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        let count = FileStorage::MAX_RECORDS as u64 + 1;
+        for time in 0..count {
+            storage.store(meas([time])).await.unwrap();
+        }
+        assert_eq!(storage.records, 1);
+        let (groups, _) = FileStorage::decode(&fs::read(&path).await.unwrap());
+        assert_eq!(groups.len(), 1);
+        drop(storage);
+
+        let storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        assert_eq!(times(&storage.measurements), Vec::from_iter(0..count));
+    }
+
+    #[tokio::test]
+    async fn remove_until_rewrites_journal() {
+        let path = journal("removed").await;
+        let mut storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        storage.store(meas([1, 2])).await.unwrap();
+        storage.store(meas([3, 4])).await.unwrap();
+
+        let guard = storage.load().await.unwrap();
+        let removed = guard
+            .remove_until(UNIX_EPOCH + Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(times(&removed), [1, 2]);
+        assert_eq!(times(&storage.measurements), [3, 4]);
+        assert_eq!(storage.records, 1);
+        drop(storage);
+
+        let storage = FileStorage::open(&path, Retention::default())
+            .await
+            .unwrap();
+        assert_eq!(times(&storage.measurements), [3, 4]);
     }
 }
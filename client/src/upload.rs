@@ -0,0 +1,230 @@
+use crate::config::RetryConfig;
+use rand::Rng;
+use rtherm_common::{ChannelId, Encoding, Measurements, Point};
+use std::{
+    mem,
+    time::{Duration, SystemTime},
+};
+
+/// Part of measurements to be sent in a single request.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub measurements: Measurements,
+    /// Time of the latest point in chunk.
+    ///
+    /// All points not later than this time are contained in this or previous chunks.
+    pub last_time: SystemTime,
+}
+
+/// Approximate size of an empty request in bytes
+const REQUEST_OVERHEAD: usize = 32;
+/// Approximate overhead of adding a channel to a request in bytes
+const CHANNEL_OVERHEAD: usize = 8;
+
+/// Split measurements into chunks in time order so that the size of each chunk
+/// serialized with `encoding` does not exceed `max_size`.
+///
+/// Points with the same time are never split between chunks,
+/// so a chunk may exceed the limit if there are too many of them.
+pub fn split_chunks(meas: Measurements, max_size: usize, encoding: Encoding) -> Vec<Chunk> {
+    let mut points: Vec<(ChannelId, Point)> = meas
+        .into_iter()
+        .flat_map(|(id, points)| points.into_iter().map(move |p| (id.clone(), p)))
+        .collect();
+    points.sort_by_key(|(_, p)| p.time);
+
+    let mut chunks = Vec::new();
+    let mut current = Measurements::default();
+    let mut current_size = REQUEST_OVERHEAD;
+    let mut points = points.into_iter().peekable();
+    while let Some((id, point)) = points.next() {
+        // Collect all points with the same time
+        let mut group = vec![(id, point)];
+        while let Some((id, p)) = points.next_if(|(_, p)| p.time == point.time) {
+            group.push((id, p));
+        }
+
+        let group_size = group
+            .iter()
+            .map(|(id, p)| {
+                point_size(encoding, p)
+                    + if current.contains_key(id) {
+                        0
+                    } else {
+                        id.len() + CHANNEL_OVERHEAD
+                    }
+            })
+            .sum::<usize>();
+        if !current.is_empty() && current_size + group_size > max_size {
+            chunks.push(Chunk {
+                last_time: last_time(&current),
+                measurements: mem::take(&mut current),
+            });
+            current_size = REQUEST_OVERHEAD;
+        }
+
+        current_size += group_size;
+        for (id, p) in group {
+            current.entry(id).or_insert_with(Vec::new).push(p);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(Chunk {
+            last_time: last_time(&current),
+            measurements: current,
+        });
+    }
+    chunks
+}
+
+/// Size of point in request including separator.
+fn point_size(encoding: Encoding, point: &Point) -> usize {
+    rtherm_common::point_size(encoding, point) + 1
+}
+
+fn last_time(meas: &Measurements) -> SystemTime {
+    meas.values()
+        .filter_map(|points| points.last())
+        .map(|p| p.time)
+        .max()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// Exponential backoff with jitter.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    config: RetryConfig,
+    /// Delay without jitter, `None` if last attempt succeeded
+    delay: Option<f64>,
+}
+
+impl Backoff {
+    pub fn new(config: RetryConfig) -> Self {
+        Self {
+            config,
+            delay: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = None;
+    }
+
+    /// Delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.delay {
+            Some(delay) => (delay * self.config.factor).min(self.config.max_delay),
+            None => self.config.initial_delay.min(self.config.max_delay),
+        };
+        self.delay = Some(delay);
+
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range((1.0 - jitter)..=(1.0 + jitter))
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64((delay * factor).clamp(0.0, self.config.max_delay.max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn channel(id: &str) -> ChannelId {
+        ChannelId::try_from(id).unwrap()
+    }
+
+    fn point(secs: u64) -> Point {
+        Point {
+            value: secs as f64,
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+        }
+    }
+
+    fn secs(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// Times of all points in chunk.
+    fn times(chunk: &Chunk) -> Vec<u64> {
+        let mut times: Vec<_> = chunk
+            .measurements
+            .values()
+            .flatten()
+            .map(|p| secs(p.time))
+            .collect();
+        times.sort();
+        times
+    }
+
+    #[test]
+    fn points_with_the_same_time_stay_together() {
+        let meas = Measurements::from_iter(
+            ["a", "b", "c"].map(|id| (channel(id), vec![point(1), point(2), point(3)])),
+        );
+        // Limit is exceeded even by a single point.
+        let chunks = split_chunks(meas, REQUEST_OVERHEAD + 40, Encoding::Json);
+        assert_eq!(chunks.len(), 3);
+        for (chunk, time) in chunks.iter().zip(1..) {
+            assert_eq!(times(chunk), [time; 3]);
+            assert_eq!(secs(chunk.last_time), time);
+        }
+    }
+
+    #[test]
+    fn last_time_is_monotonic() {
+        let meas = Measurements::from_iter([
+            (channel("a"), (0..50).map(|t| point(2 * t)).collect()),
+            (channel("b"), (0..50).map(|t| point(3 * t)).collect()),
+        ]);
+        let chunks = split_chunks(meas, 256, Encoding::Json);
+        assert!(chunks.len() > 1);
+        let mut total = 0;
+        let mut prev = None;
+        for chunk in &chunks {
+            let last = secs(chunk.last_time);
+            let times = times(chunk);
+            assert_eq!(times.last(), Some(&last));
+            if let Some(prev) = prev {
+                assert!(last > prev);
+                // Points not later than previous last time are all in previous chunks.
+                assert!(times[0] > prev);
+            }
+            prev = Some(last);
+            total += times.len();
+        }
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn oversized_group_is_chunk_of_its_own() {
+        let ids: Vec<_> = (0..20).map(|i| format!("channel_{i}")).collect();
+        let mut meas = Measurements::from_iter(ids.iter().map(|id| (channel(id), vec![point(2)])));
+        meas.get_mut(&channel("channel_0"))
+            .unwrap()
+            .extend([point(1), point(3)]);
+        meas.get_mut(&channel("channel_0"))
+            .unwrap()
+            .sort_by_key(|p| p.time);
+
+        let chunks = split_chunks(meas, 128, Encoding::Json);
+        let times: Vec<_> = chunks.iter().map(times).collect();
+        assert_eq!(times, [vec![1], vec![2; 20], vec![3]]);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn size_depends_on_encoding() {
+        let meas = Measurements::from_iter([(channel("a"), (0..100).map(point).collect())]);
+        let json = split_chunks(meas.clone(), 1024, Encoding::Json);
+        let msgpack = split_chunks(meas, 1024, Encoding::Msgpack);
+        assert!(msgpack.len() < json.len());
+        for chunk in msgpack {
+            let request = rtherm_common::ProvideRequest::new(chunk.measurements);
+            assert!(request.encode(Encoding::Msgpack).unwrap().len() <= 1024);
+        }
+    }
+}
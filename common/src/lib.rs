@@ -1,4 +1,8 @@
 pub mod error;
+pub mod signature;
+mod wire;
+
+pub use wire::{point_size, CodecError, Encoding, WireError, PROTOCOL_VERSION};
 
 use serde::{Deserialize, Serialize};
 use std::{
@@ -97,9 +101,64 @@ pub fn merge_groups<K: Eq + Hash>(
     accum
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Identifier of measurements batch used to detect duplicate requests.
+pub type BatchId = u64;
+
+/// Serialized according to current [`PROTOCOL_VERSION`].
+///
+/// Requests of older versions are also accepted on deserialization.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "wire::RawProvideRequest")]
 pub struct ProvideRequest {
     pub measurements: Measurements,
+    /// Requests with the same batch id are applied only once.
+    pub batch_id: Option<BatchId>,
+}
+
+impl ProvideRequest {
+    /// Create request with batch id derived from `measurements`.
+    ///
+    /// Resending the same measurements produces the same batch id.
+    /// It only detects exact retries, points sent again in a different batch
+    /// are deduplicated by channel and time when stored in database.
+    pub fn new(measurements: Measurements) -> Self {
+        Self {
+            batch_id: Some(batch_id(&measurements)),
+            measurements,
+        }
+    }
+}
+
+/// Compute batch id as FNV-1a hash of measurements.
+///
+/// Result does not depend on channel order, but depends on order of points.
+pub fn batch_id(meas: &Measurements) -> BatchId {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let mut hash = OFFSET;
+    let mut write = |bytes: &[u8]| {
+        for &b in bytes {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    };
+
+    let mut channels: Vec<_> = meas.iter().collect();
+    channels.sort_by_key(|(id, _)| *id);
+    for (id, points) in channels {
+        write(id.as_bytes());
+        write(&[0xff]);
+        for p in points {
+            let time = p
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            write(&p.value.to_bits().to_le_bytes());
+            write(&time.as_secs().to_le_bytes());
+            write(&time.subsec_nanos().to_le_bytes());
+        }
+    }
+    hash
 }
 
 mod unix_secs {
@@ -0,0 +1,38 @@
+//! HMAC-SHA256 signatures of request bodies.
+//!
+//! Signature covers timestamp, nonce and body, so that the same signed body cannot be replayed
+//! after timestamp window expires or with the same nonce.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const KEY_ID_HEADER: &str = "X-Rtherm-Key-Id";
+/// Seconds since Unix epoch
+pub const TIMESTAMP_HEADER: &str = "X-Rtherm-Timestamp";
+pub const NONCE_HEADER: &str = "X-Rtherm-Nonce";
+/// Hex-encoded signature
+pub const SIGNATURE_HEADER: &str = "X-Rtherm-Signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], timestamp: u64, nonce: &str, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(format!("{timestamp}\n{nonce}\n").as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Compute hex-encoded signature.
+pub fn sign(key: &[u8], timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    hex::encode(mac(key, timestamp, nonce, body).finalize().into_bytes())
+}
+
+/// Check hex-encoded signature in constant time.
+pub fn verify(key: &[u8], timestamp: u64, nonce: &str, body: &[u8], signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(bytes) => mac(key, timestamp, nonce, body)
+            .verify_slice(&bytes)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
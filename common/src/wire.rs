@@ -0,0 +1,311 @@
+//! Versioned wire format of [`ProvideRequest`].
+
+use crate::{BatchId, ChannelId, Measurements, Point, ProvideRequest};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use std::error::Error;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    time::{Duration, SystemTime},
+};
+
+/// Current version of wire format.
+///
+/// + `1` - point time is an integer number of seconds since Unix epoch.
+/// + `2` - point time is an integer number of nanoseconds since Unix epoch, named `time_ns`,
+///   so that servers supporting only version `1` reject it instead of misreading.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Version assumed when it is not specified in request.
+const LEGACY_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Serialize)]
+struct RawPoint {
+    value: f64,
+    /// Nanoseconds since Unix epoch
+    time_ns: u64,
+}
+
+impl RawPoint {
+    fn encode(point: &Point) -> Self {
+        Self {
+            value: point.value,
+            time_ns: point
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|dur| u64::try_from(dur.as_nanos()).unwrap_or(u64::MAX))
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Point of any version, only the time field of that version must be present.
+#[derive(Clone, Copy, Deserialize)]
+struct AnyRawPoint {
+    value: f64,
+    #[serde(default)]
+    time_ns: Option<u64>,
+    /// Seconds since Unix epoch in legacy version
+    #[serde(default)]
+    time: Option<u64>,
+}
+
+impl AnyRawPoint {
+    fn decode(self, version: u32) -> Result<Point, WireError> {
+        let since_epoch = match (version, self.time, self.time_ns) {
+            (LEGACY_VERSION, Some(secs), None) => Duration::from_secs(secs),
+            (LEGACY_VERSION, ..) => return Err(WireError::TimeField(version, "time")),
+            (_, None, Some(nanos)) => Duration::from_nanos(nanos),
+            _ => return Err(WireError::TimeField(version, "time_ns")),
+        };
+        Ok(Point {
+            value: self.value,
+            time: SystemTime::UNIX_EPOCH + since_epoch,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RawProvideRequest {
+    #[serde(default = "legacy_version")]
+    version: u32,
+    measurements: HashMap<ChannelId, Vec<AnyRawPoint>>,
+    #[serde(default)]
+    batch_id: Option<BatchId>,
+}
+
+fn legacy_version() -> u32 {
+    LEGACY_VERSION
+}
+
+impl TryFrom<RawProvideRequest> for ProvideRequest {
+    type Error = WireError;
+    fn try_from(raw: RawProvideRequest) -> Result<Self, Self::Error> {
+        if !(LEGACY_VERSION..=PROTOCOL_VERSION).contains(&raw.version) {
+            return Err(WireError::UnsupportedVersion(raw.version));
+        }
+        Ok(Self {
+            measurements: raw
+                .measurements
+                .into_iter()
+                .map(|(id, points)| {
+                    let points = points
+                        .into_iter()
+                        .map(|p| p.decode(raw.version))
+                        .collect::<Result<_, _>>()?;
+                    Ok((id, points))
+                })
+                .collect::<Result<_, _>>()?,
+            batch_id: raw.batch_id,
+        })
+    }
+}
+
+/// Size of point encoded in request of current version with `encoding`.
+pub fn point_size(encoding: Encoding, point: &Point) -> usize {
+    let point = RawPoint::encode(point);
+    match encoding {
+        Encoding::Json => serde_json::to_vec(&point).map(|v| v.len()).unwrap_or(0),
+        #[cfg(feature = "msgpack")]
+        Encoding::Msgpack => rmp_serde::to_vec(&point).map(|v| v.len()).unwrap_or(0),
+    }
+}
+
+struct RawPoints<'a>(&'a [Point]);
+
+impl Serialize for RawPoints<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(RawPoint::encode))
+    }
+}
+
+struct RawMeasurements<'a>(&'a Measurements);
+
+impl Serialize for RawMeasurements<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(id, points)| (id, RawPoints(points))))
+    }
+}
+
+impl Serialize for ProvideRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ProvideRequest", 3)?;
+        state.serialize_field("version", &PROTOCOL_VERSION)?;
+        state.serialize_field("measurements", &RawMeasurements(&self.measurements))?;
+        state.serialize_field("batch_id", &self.batch_id)?;
+        state.end()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum WireError {
+    UnsupportedVersion(u32),
+    /// Point does not have the time field of request version, or has field of another version.
+    TimeField(u32, &'static str),
+}
+impl Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported protocol version {}, expected version from {} to {}",
+                version, LEGACY_VERSION, PROTOCOL_VERSION
+            ),
+            Self::TimeField(version, field) => write!(
+                f,
+                "Point time must be given only in field `{field}` in protocol version {version}"
+            ),
+        }
+    }
+}
+impl Error for WireError {}
+
+/// Encoding of request body.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// Points are encoded as arrays without field names.
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Encoding::Msgpack => "application/msgpack",
+        }
+    }
+
+    /// Find encoding by MIME type, parameters like `charset` are ignored.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Encoding::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" => Some(Encoding::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    #[cfg(feature = "msgpack")]
+    MsgpackEncode(rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    MsgpackDecode(rmp_serde::decode::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "JSON error: {e}"),
+            #[cfg(feature = "msgpack")]
+            CodecError::MsgpackEncode(e) => write!(f, "MessagePack encode error: {e}"),
+            #[cfg(feature = "msgpack")]
+            CodecError::MsgpackDecode(e) => write!(f, "MessagePack decode error: {e}"),
+        }
+    }
+}
+impl Error for CodecError {}
+
+impl ProvideRequest {
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, CodecError> {
+        match encoding {
+            Encoding::Json => serde_json::to_vec(self).map_err(CodecError::Json),
+            #[cfg(feature = "msgpack")]
+            Encoding::Msgpack => rmp_serde::to_vec(self).map_err(CodecError::MsgpackEncode),
+        }
+    }
+
+    pub fn decode(encoding: Encoding, bytes: &[u8]) -> Result<Self, CodecError> {
+        match encoding {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(CodecError::Json),
+            #[cfg(feature = "msgpack")]
+            Encoding::Msgpack => rmp_serde::from_slice(bytes).map_err(CodecError::MsgpackDecode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_and_current_point_time() {
+        let legacy: ProvideRequest =
+            serde_json::from_str(r#"{"measurements":{"a":[{"value":1.0,"time":2}]}}"#).unwrap();
+        let point = legacy.measurements[&ChannelId::try_from("a").unwrap()][0];
+        assert_eq!(point.time, SystemTime::UNIX_EPOCH + Duration::from_secs(2));
+
+        let json = serde_json::to_string(&legacy).unwrap();
+        assert!(json.contains(r#""time_ns":2000000000"#), "{json}");
+        let current: ProvideRequest = serde_json::from_str(&json).unwrap();
+        let point = current.measurements[&ChannelId::try_from("a").unwrap()][0];
+        assert_eq!(point.time, SystemTime::UNIX_EPOCH + Duration::from_secs(2));
+    }
+
+    fn decode(json: &str) -> Result<ProvideRequest, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    fn time(request: &ProvideRequest) -> SystemTime {
+        request.measurements[&ChannelId::try_from("a").unwrap()][0].time
+    }
+
+    #[test]
+    fn each_version_accepts_only_its_time_field() {
+        let legacy = decode(r#"{"version":1,"measurements":{"a":[{"value":1.0,"time":2}]}}"#);
+        assert_eq!(
+            time(&legacy.unwrap()),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2)
+        );
+        let current = decode(r#"{"version":2,"measurements":{"a":[{"value":1.0,"time_ns":2}]}}"#);
+        assert_eq!(
+            time(&current.unwrap()),
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(2)
+        );
+
+        for json in [
+            r#"{"version":1,"measurements":{"a":[{"value":1.0,"time_ns":2}]}}"#,
+            r#"{"measurements":{"a":[{"value":1.0,"time_ns":2}]}}"#,
+            r#"{"version":2,"measurements":{"a":[{"value":1.0,"time":2}]}}"#,
+            r#"{"version":1,"measurements":{"a":[{"value":1.0,"time":2,"time_ns":2}]}}"#,
+            r#"{"version":2,"measurements":{"a":[{"value":1.0,"time":2,"time_ns":2}]}}"#,
+            r#"{"version":2,"measurements":{"a":[{"value":1.0}]}}"#,
+        ] {
+            let e = decode(json).unwrap_err();
+            assert!(e.to_string().contains("Point time"), "{json}: {e}");
+        }
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let e = decode(r#"{"version":3,"measurements":{}}"#).unwrap_err();
+        assert!(
+            e.to_string().contains("Unsupported protocol version 3"),
+            "{e}"
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_points_are_positional() {
+        let request = ProvideRequest::new(Measurements::from_iter([(
+            ChannelId::try_from("a").unwrap(),
+            vec![Point {
+                value: 1.0,
+                time: SystemTime::UNIX_EPOCH + Duration::from_nanos(2),
+            }],
+        )]));
+        let bytes = request.encode(Encoding::Msgpack).unwrap();
+        let decoded = ProvideRequest::decode(Encoding::Msgpack, &bytes).unwrap();
+        assert_eq!(time(&decoded), time(&request));
+        assert_eq!(decoded.batch_id, request.batch_id);
+    }
+}
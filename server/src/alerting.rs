@@ -0,0 +1,1229 @@
+use futures::{future::join_all, FutureExt};
+use rtherm_common::{error::AnyError, ChannelId, Measurements, Point};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    future::Future,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::spawn,
+    time::sleep,
+};
+
+use crate::{
+    config::AlertingConfig,
+    recepient::Recepient,
+    statistics::{ChannelHistory, RangeExt},
+    storage::{AnyStorage, Stored, StoredLock},
+};
+
+/// Rule of alerting about values of a channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    /// Range of good values for a channel.
+    ///
+    /// Values outside of this range considered to be bad.
+    pub normal_range: RangeInclusive<f64>,
+    /// Limits of value change over time window.
+    #[serde(default)]
+    pub rate: Option<RateRule>,
+    /// When violations are alerted.
+    #[serde(default)]
+    pub debounce: Debounce,
+    /// Interval of reminders while violation lasts, in seconds.
+    #[serde(default)]
+    pub remind_interval: Option<f64>,
+    /// Escalation of alerts that are not acknowledged in time.
+    #[serde(default)]
+    pub escalation: Option<Escalation>,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            normal_range: 30.0..=80.0,
+            rate: None,
+            debounce: Debounce::default(),
+            remind_interval: None,
+            escalation: None,
+        }
+    }
+}
+
+/// Limits of value change over time window, e.g. "falls more than 10 °C in 15 min".
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateRule {
+    /// Window over which change is computed, in seconds.
+    pub window: f64,
+    /// Max allowed decrease of value over window.
+    pub max_fall: Option<f64>,
+    /// Max allowed increase of value over window.
+    pub max_rise: Option<f64>,
+}
+
+/// Requirements for violation to be alerted, suppress alerts caused by noisy values.
+///
+/// By default every violation is alerted immediately.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Debounce {
+    /// Number of consecutive violating readings required to alert.
+    pub min_points: u32,
+    /// Duration that violation must last to alert, in seconds.
+    pub min_duration: f64,
+    /// Min time between alerts of the same condition, in seconds.
+    ///
+    /// Violation that occurs earlier is alerted when interval elapses, if it still lasts.
+    pub realert_interval: f64,
+}
+
+/// Escalation of alerts to additional targets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Escalation {
+    /// Time to wait for acknowledgement of alert, in seconds.
+    pub timeout: f64,
+    /// Targets of the same notifier to alert additionally.
+    pub targets: Vec<String>,
+}
+
+/// Part of rule that is violated and recovered independently.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Range,
+    Fall,
+    Rise,
+}
+
+/// Status of alert about a condition.
+///
+/// Debounce is evaluated by time of points, while reminders and escalation by wall-clock time.
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AlertStatus {
+    /// Condition is satisfied
+    #[default]
+    Normal,
+    /// Violation is observed, but not alerted yet because of debounce
+    Pending { streak: u32, since: SystemTime },
+    /// Violation is alerted and waits for acknowledgement
+    Firing {
+        event: AlertEvent,
+        fired: SystemTime,
+        reminded: SystemTime,
+        escalated: bool,
+    },
+    /// Violation is acknowledged, so that no reminders and escalations are sent
+    Acknowledged { escalated: bool },
+}
+
+impl AlertStatus {
+    pub fn is_escalated(&self) -> bool {
+        matches!(
+            self,
+            Self::Firing {
+                escalated: true,
+                ..
+            } | Self::Acknowledged { escalated: true }
+        )
+    }
+}
+
+/// State of a condition of rule for a single target.
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ConditionState {
+    #[serde(flatten)]
+    pub status: AlertStatus,
+    /// Time of the last reading that has been alerted as violation
+    pub alerted: Option<SystemTime>,
+}
+
+impl ConditionState {
+    /// Apply reading of condition taken at `time`.
+    ///
+    /// `violated` tells whether reading breaks condition
+    /// and `recovered` whether it is good enough to recover from violation.
+    /// If condition changes from good to bad or vice versa then event created by `event` is returned.
+    fn observe(
+        &mut self,
+        violated: bool,
+        recovered: bool,
+        time: SystemTime,
+        debounce: &Debounce,
+        event: impl FnOnce(bool) -> Option<AlertEvent>,
+    ) -> Option<AlertEvent> {
+        let (streak, since) = match self.status {
+            AlertStatus::Firing { .. } | AlertStatus::Acknowledged { .. } => {
+                if !recovered {
+                    return None;
+                }
+                self.status = AlertStatus::Normal;
+                return event(false);
+            }
+            _ if !violated => {
+                self.status = AlertStatus::Normal;
+                return None;
+            }
+            AlertStatus::Normal => (1, time),
+            AlertStatus::Pending { streak, since } => (streak + 1, since),
+        };
+        let elapsed = |from: SystemTime| time.duration_since(from).unwrap_or_default();
+        let alert = streak >= debounce.min_points
+            && elapsed(since).as_secs_f64() >= debounce.min_duration
+            && self
+                .alerted
+                .is_none_or(|alerted| elapsed(alerted).as_secs_f64() >= debounce.realert_interval);
+        if !alert {
+            self.status = AlertStatus::Pending { streak, since };
+            return None;
+        }
+        let event = event(true)?;
+        let now = SystemTime::now();
+        self.status = AlertStatus::Firing {
+            event: event.clone(),
+            fired: now,
+            reminded: now,
+            escalated: false,
+        };
+        self.alerted = Some(time);
+        Some(event)
+    }
+}
+
+impl Rule {
+    /// Check new points of channel against each condition of rule, updating `states` of conditions.
+    ///
+    /// `points` must be sorted by time and `history` must already contain them.
+    /// Range is checked for every point, while rate is checked once for the whole update.
+    /// Returns events of conditions that change state from good to bad or vice versa.
+    pub fn check(
+        &self,
+        channel: &ChannelId,
+        states: &mut HashMap<Condition, ConditionState>,
+        points: &[Point],
+        history: &ChannelHistory,
+        hysteresis: f64,
+    ) -> Vec<(Condition, AlertEvent)> {
+        let mut events = Vec::new();
+        let recovery_range = self.normal_range.widen(-hysteresis);
+        let state = states.entry(Condition::Range).or_default();
+        for point in points {
+            let violated = !self.normal_range.contains(&point.value);
+            let recovered = recovery_range.contains(&point.value);
+            let event = state.observe(violated, recovered, point.time, &self.debounce, |bad| {
+                let kind = if bad {
+                    AlertKind::OutOfRange
+                } else {
+                    AlertKind::BackToNormal
+                };
+                AlertEvent::new(
+                    kind,
+                    channel.clone(),
+                    Some(&(point.value..=point.value)),
+                    Some(&self.normal_range),
+                )
+            });
+            events.extend(event.map(|event| (Condition::Range, event)));
+        }
+
+        if let (Some(rate), Some(last)) = (&self.rate, points.last()) {
+            let window = Duration::from_secs_f64(rate.window);
+            if let Some(change) = history.change(window) {
+                for (condition, limit, amount) in [
+                    (Condition::Fall, rate.max_fall, -change),
+                    (Condition::Rise, rate.max_rise, change),
+                ] {
+                    let Some(limit) = limit else {
+                        continue;
+                    };
+                    let violated = amount > limit;
+                    let recovered = amount <= (limit - hysteresis).max(0.0);
+                    let state = states.entry(condition).or_default();
+                    let event =
+                        state.observe(violated, recovered, last.time, &self.debounce, |bad| {
+                            let kind = if bad {
+                                AlertKind::RateExceeded
+                            } else {
+                                AlertKind::RateNormal
+                            };
+                            AlertEvent::rate(kind, channel.clone(), change, rate.window, limit)
+                        });
+                    events.extend(event.map(|event| (condition, event)));
+                }
+            }
+        }
+        events
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    OutOfRange,
+    BackToNormal,
+    /// Value changes faster than allowed
+    RateExceeded,
+    RateNormal,
+    Offline,
+    Online,
+}
+
+impl AlertKind {
+    /// Whether event means that condition becomes violated.
+    pub fn is_bad(&self) -> bool {
+        matches!(self, Self::OutOfRange | Self::RateExceeded | Self::Offline)
+    }
+}
+
+impl Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OutOfRange => "out_of_range",
+            Self::BackToNormal => "back_to_normal",
+            Self::RateExceeded => "rate_exceeded",
+            Self::RateNormal => "rate_normal",
+            Self::Offline => "offline",
+            Self::Online => "online",
+        })
+    }
+}
+
+/// Human-readable duration of window.
+pub fn display_window(secs: f64) -> String {
+    if secs >= 60.0 && secs % 60.0 == 0.0 {
+        format!("{} min", secs / 60.0)
+    } else {
+        format!("{secs} s")
+    }
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Alert event sent to notifiers.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AlertEvent {
+    pub event: AlertKind,
+    pub channel: ChannelId,
+    /// Min and max of values that caused event
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub normal_range: Option<[f64; 2]>,
+    /// Change of value over window, for rate events
+    pub change: Option<f64>,
+    /// Window of rate rule, in seconds
+    pub window: Option<f64>,
+    /// Max allowed change over window
+    pub limit: Option<f64>,
+    /// Time of event in seconds since Unix epoch
+    pub time: f64,
+    /// Human-readable description
+    pub message: String,
+    /// Number of reminder about violation, 0 for the first alert
+    pub reminder: u32,
+    /// Whether event is sent because alert has been escalated
+    pub escalated: bool,
+}
+
+impl AlertEvent {
+    fn with_message(event: AlertKind, channel: ChannelId, message: String) -> Self {
+        Self {
+            event,
+            channel,
+            min: None,
+            max: None,
+            normal_range: None,
+            change: None,
+            window: None,
+            limit: None,
+            time: now_secs(),
+            message,
+            reminder: 0,
+            escalated: false,
+        }
+    }
+
+    /// Event of range or online status.
+    ///
+    /// Returns `None` if values required by `event` are missing or it is a rate event.
+    pub fn new(
+        event: AlertKind,
+        channel: ChannelId,
+        values: Option<&RangeInclusive<f64>>,
+        normal_range: Option<&RangeInclusive<f64>>,
+    ) -> Option<Self> {
+        let message = match event {
+            AlertKind::OutOfRange => format!(
+                "{channel} value {} is out of normal range {}",
+                values?.display(),
+                normal_range?.display()
+            ),
+            AlertKind::BackToNormal => format!(
+                "{channel} value {} returned to normal range {}",
+                values?.display(),
+                normal_range?.display()
+            ),
+            AlertKind::Offline => format!("{channel} is offline"),
+            AlertKind::Online => format!("{channel} is online (value: {})", values?.display()),
+            AlertKind::RateExceeded | AlertKind::RateNormal => return None,
+        };
+        Some(Self {
+            min: values.map(|r| *r.start()),
+            max: values.map(|r| *r.end()),
+            normal_range: normal_range.map(|r| [*r.start(), *r.end()]),
+            ..Self::with_message(event, channel, message)
+        })
+    }
+
+    /// Event of rate rule.
+    ///
+    /// Returns `None` if `event` is not a rate event.
+    pub fn rate(
+        event: AlertKind,
+        channel: ChannelId,
+        change: f64,
+        window: f64,
+        limit: f64,
+    ) -> Option<Self> {
+        let direction = if change < 0.0 { "fell" } else { "rose" };
+        let message = match event {
+            AlertKind::RateExceeded => format!(
+                "{channel} value {direction} by {:.1} in {}, more than {limit}",
+                change.abs(),
+                display_window(window),
+            ),
+            AlertKind::RateNormal => format!(
+                "{channel} value change returned to normal ({change:+.1} in {})",
+                display_window(window),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            change: Some(change),
+            window: Some(window),
+            limit: Some(limit),
+            ..Self::with_message(event, channel, message)
+        })
+    }
+
+    /// The same event sent again at current time.
+    fn repeat(&self, escalated: bool) -> Self {
+        Self {
+            time: now_secs(),
+            escalated,
+            ..self.clone()
+        }
+    }
+
+    pub fn values(&self) -> Option<RangeInclusive<f64>> {
+        Some(self.min?..=self.max?)
+    }
+    pub fn normal_range(&self) -> Option<RangeInclusive<f64>> {
+        self.normal_range.map(|[start, end]| start..=end)
+    }
+}
+
+/// Target of notifier that is subscribed to a channel.
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    /// Identifier of target within notifier, e.g. chat or endpoint
+    pub target: String,
+    pub rule: Rule,
+}
+
+/// Destination of alert events.
+pub trait Notifier: Send + Sync {
+    type Error: Error + Send;
+    /// Targets that are subscribed to `channel`.
+    fn subscriptions(
+        &self,
+        channel: ChannelId,
+    ) -> impl Future<Output = Vec<Subscription>> + Send + '_;
+    /// Deliver `event` to `target`.
+    fn notify(
+        &self,
+        target: String,
+        event: AlertEvent,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + '_;
+    /// Acknowledgements sent by targets, if notifier supports them.
+    ///
+    /// Called once when alerting is started.
+    fn acknowledgements(&self) -> Option<mpsc::UnboundedReceiver<Ack>> {
+        None
+    }
+}
+
+/// Request of target to acknowledge its alerts, so that no reminders and escalations are sent.
+///
+/// Escalation targets can acknowledge alerts escalated to them.
+#[derive(Debug)]
+pub struct Ack {
+    pub target: String,
+    /// Channel to acknowledge alerts of, all channels if not set
+    pub channel: Option<ChannelId>,
+    /// Receives number of acknowledged alerts
+    pub reply: oneshot::Sender<usize>,
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+trait DynNotifier: Send + Sync {
+    fn subscriptions_dyn(&self, channel: ChannelId) -> BoxFuture<'_, Vec<Subscription>>;
+    fn notify_dyn(&self, target: String, event: AlertEvent) -> BoxFuture<'_, Result<(), AnyError>>;
+    fn acknowledgements_dyn(&self) -> Option<mpsc::UnboundedReceiver<Ack>>;
+}
+
+impl<N: Notifier<Error: 'static>> DynNotifier for N {
+    fn subscriptions_dyn(&self, channel: ChannelId) -> BoxFuture<'_, Vec<Subscription>> {
+        Box::pin(self.subscriptions(channel))
+    }
+    fn notify_dyn(&self, target: String, event: AlertEvent) -> BoxFuture<'_, Result<(), AnyError>> {
+        Box::pin(self.notify(target, event).map(|r| r.map_err(AnyError::new)))
+    }
+    fn acknowledgements_dyn(&self) -> Option<mpsc::UnboundedReceiver<Ack>> {
+        self.acknowledgements()
+    }
+}
+
+pub struct AnyNotifier(Box<dyn DynNotifier>);
+
+impl AnyNotifier {
+    pub fn new<N: Notifier<Error: 'static> + 'static>(notifier: N) -> Self {
+        Self(Box::new(notifier))
+    }
+}
+
+impl Notifier for AnyNotifier {
+    type Error = AnyError;
+    fn subscriptions(
+        &self,
+        channel: ChannelId,
+    ) -> impl Future<Output = Vec<Subscription>> + Send + '_ {
+        self.0.subscriptions_dyn(channel)
+    }
+    fn notify(
+        &self,
+        target: String,
+        event: AlertEvent,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + '_ {
+        self.0.notify_dyn(target, event)
+    }
+    fn acknowledgements(&self) -> Option<mpsc::UnboundedReceiver<Ack>> {
+        self.0.acknowledgements_dyn()
+    }
+}
+
+#[derive(Default, Debug)]
+struct ChannelState {
+    /// Recent values used to evaluate rate rules
+    values: ChannelHistory,
+    last_update: Option<Instant>,
+    online: bool,
+}
+
+/// Alerting state that is kept across restarts.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+struct AlertState {
+    /// States of conditions of channel for each target, as `<notifier>/<target>`
+    conditions: HashMap<ChannelId, HashMap<String, HashMap<Condition, ConditionState>>>,
+}
+
+/// Notifier failed to deliver alert event.
+#[derive(Debug)]
+pub struct NotifyError {
+    pub notifier: String,
+    pub source: AnyError,
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Notifier {} error: {}", self.notifier, self.source)
+    }
+}
+
+impl Error for NotifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Alert event addressed to target of notifier.
+struct Alert {
+    notifier: usize,
+    target: String,
+    event: AlertEvent,
+}
+
+/// Period of checking alerts that need reminder or escalation.
+const REMIND_PERIOD: Duration = Duration::from_secs(10);
+
+/// Alerting subsystem.
+///
+/// Evaluates rules of subscribed targets on each channel update and on channel timeout,
+/// and sends resulting events to notifiers.
+/// Alerts that are not resolved are repeated and escalated according to rules until acknowledged.
+#[derive(Clone)]
+pub struct Alerting {
+    notifiers: Arc<Vec<(String, AnyNotifier)>>,
+    offline_timeout: Duration,
+    hysteresis: f64,
+    channels: Arc<Mutex<HashMap<ChannelId, ChannelState>>>,
+    state: Arc<StoredLock<AlertState, AnyStorage>>,
+}
+
+impl Alerting {
+    pub async fn new(
+        config: AlertingConfig,
+        notifiers: Vec<(String, AnyNotifier)>,
+        storage: AnyStorage,
+    ) -> Self {
+        let state = Stored::load_or_default("alerting-state".to_string(), storage).await;
+        let this = Self {
+            notifiers: Arc::new(notifiers),
+            offline_timeout: Duration::from_secs_f64(config.offline_timeout),
+            hysteresis: config.hysteresis,
+            channels: Arc::default(),
+            state: Arc::new(StoredLock::new(state)),
+        };
+        spawn(this.clone().monitor());
+        spawn(this.clone().remind());
+        for (i, (_, notifier)) in this.notifiers.iter().enumerate() {
+            if let Some(acks) = notifier.acknowledgements() {
+                spawn(this.clone().receive_acks(i, acks));
+            }
+        }
+        this
+    }
+
+    /// Subscriptions of all notifiers to channel.
+    async fn subscriptions(&self, channel: &ChannelId) -> Vec<(usize, Subscription)> {
+        let mut subscriptions = Vec::new();
+        for (i, (_, notifier)) in self.notifiers.iter().enumerate() {
+            subscriptions.extend(
+                notifier
+                    .subscriptions(channel.clone())
+                    .await
+                    .into_iter()
+                    .map(|sub| (i, sub)),
+            );
+        }
+        subscriptions
+    }
+
+    fn state_key(&self, notifier: usize, target: &str) -> String {
+        format!("{}/{target}", self.notifiers[notifier].0)
+    }
+
+    async fn monitor(self) -> ! {
+        loop {
+            sleep(self.offline_timeout / 2).await;
+
+            let mut offline = Vec::new();
+            {
+                let mut channels = self.channels.lock().await;
+                for (channel_id, channel) in channels.iter_mut() {
+                    if let Some(last_update) = channel.last_update {
+                        if channel.online && last_update + self.offline_timeout < Instant::now() {
+                            channel.online = false;
+                            offline.push(channel_id.clone());
+                        }
+                    }
+                }
+            }
+
+            let mut alerts = Vec::new();
+            for channel_id in offline {
+                let Some(event) =
+                    AlertEvent::new(AlertKind::Offline, channel_id.clone(), None, None)
+                else {
+                    continue;
+                };
+                for (notifier, sub) in self.subscriptions(&channel_id).await {
+                    alerts.push(Alert {
+                        notifier,
+                        target: sub.target,
+                        event: event.clone(),
+                    });
+                }
+            }
+            for err in self.dispatch(alerts).await {
+                log::error!("{err}");
+            }
+        }
+    }
+
+    /// Send reminders about firing alerts and escalate those that are not acknowledged in time.
+    async fn remind(self) -> ! {
+        loop {
+            sleep(REMIND_PERIOD).await;
+
+            let firing = self.state.read().await.conditions.values().any(|targets| {
+                targets.values().any(|conditions| {
+                    conditions
+                        .values()
+                        .any(|c| matches!(c.status, AlertStatus::Firing { .. }))
+                })
+            });
+            if !firing {
+                continue;
+            }
+
+            let alerts = self.reminders(SystemTime::now()).await;
+            for err in self.dispatch(alerts).await {
+                log::error!("{err}");
+            }
+        }
+    }
+
+    /// Firing alerts that are due for reminder or escalation at `now`.
+    async fn reminders(&self, now: SystemTime) -> Vec<Alert> {
+        let elapsed = |from: SystemTime| now.duration_since(from).unwrap_or_default();
+        let mut alerts = Vec::new();
+        let mut state = self.state.write().await;
+        for (channel_id, targets) in state.conditions.iter_mut() {
+            for (notifier, sub) in self.subscriptions(channel_id).await {
+                let Some(conditions) = targets.get_mut(&self.state_key(notifier, &sub.target))
+                else {
+                    continue;
+                };
+                let escalation_targets = match &sub.rule.escalation {
+                    Some(escalation) => escalation.targets.as_slice(),
+                    None => &[],
+                };
+                for condition in conditions.values_mut() {
+                    let AlertStatus::Firing {
+                        event,
+                        fired,
+                        reminded,
+                        escalated,
+                    } = &mut condition.status
+                    else {
+                        continue;
+                    };
+                    let escalate = !*escalated
+                        && sub.rule.escalation.as_ref().is_some_and(|escalation| {
+                            elapsed(*fired).as_secs_f64() >= escalation.timeout
+                        });
+                    if escalate {
+                        for target in escalation_targets {
+                            alerts.push(Alert {
+                                notifier,
+                                target: target.clone(),
+                                event: event.repeat(true),
+                            });
+                        }
+                    }
+                    let remind = sub
+                        .rule
+                        .remind_interval
+                        .is_some_and(|interval| elapsed(*reminded).as_secs_f64() >= interval);
+                    if remind {
+                        event.reminder += 1;
+                        *reminded = now;
+                        alerts.push(Alert {
+                            notifier,
+                            target: sub.target.clone(),
+                            event: event.repeat(false),
+                        });
+                        // Just escalated targets have already received this alert.
+                        if *escalated {
+                            for target in escalation_targets {
+                                alerts.push(Alert {
+                                    notifier,
+                                    target: target.clone(),
+                                    event: event.repeat(true),
+                                });
+                            }
+                        }
+                    }
+                    *escalated |= escalate;
+                }
+            }
+        }
+        state.async_drop().await;
+        alerts
+    }
+
+    async fn receive_acks(self, notifier: usize, mut acks: mpsc::UnboundedReceiver<Ack>) {
+        while let Some(ack) = acks.recv().await {
+            let count = self
+                .acknowledge(notifier, &ack.target, ack.channel.as_ref())
+                .await;
+            // Target could give up waiting for reply.
+            let _ = ack.reply.send(count);
+        }
+    }
+
+    /// Acknowledge firing alerts of target and alerts escalated to it.
+    ///
+    /// Returns number of acknowledged alerts.
+    async fn acknowledge(
+        &self,
+        notifier: usize,
+        target: &str,
+        channel: Option<&ChannelId>,
+    ) -> usize {
+        let mut count = 0;
+        let mut state = self.state.write().await;
+        for (channel_id, targets) in state.conditions.iter_mut() {
+            if channel.is_some_and(|channel| channel != channel_id) {
+                continue;
+            }
+            for (i, sub) in self.subscriptions(channel_id).await {
+                if i != notifier {
+                    continue;
+                }
+                let Some(conditions) = targets.get_mut(&self.state_key(notifier, &sub.target))
+                else {
+                    continue;
+                };
+                let is_escalation_target = sub
+                    .rule
+                    .escalation
+                    .as_ref()
+                    .is_some_and(|escalation| escalation.targets.iter().any(|t| t == target));
+                for condition in conditions.values_mut() {
+                    if let AlertStatus::Firing { escalated, .. } = condition.status {
+                        if sub.target == target || (escalated && is_escalation_target) {
+                            condition.status = AlertStatus::Acknowledged { escalated };
+                            count += 1;
+                        }
+                    }
+                }
+            }
+        }
+        state.async_drop().await;
+        count
+    }
+
+    /// Send alerts to notifiers.
+    ///
+    /// Notifiers are called concurrently, but alerts of each notifier are sent in order.
+    async fn dispatch(&self, alerts: Vec<Alert>) -> Vec<NotifyError> {
+        let mut by_notifier = HashMap::<usize, Vec<Alert>>::new();
+        for alert in alerts {
+            by_notifier.entry(alert.notifier).or_default().push(alert);
+        }
+        join_all(by_notifier.into_iter().map(|(i, alerts)| async move {
+            let (name, notifier) = &self.notifiers[i];
+            let mut errors = Vec::new();
+            for alert in alerts {
+                if let Err(source) = notifier.notify(alert.target, alert.event).await {
+                    errors.push(NotifyError {
+                        notifier: name.clone(),
+                        source,
+                    });
+                }
+            }
+            errors
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Recepient for Alerting {
+    type Error = NotifyError;
+
+    async fn update(&mut self, measurements: Measurements) -> Vec<Self::Error> {
+        let mut alerts = Vec::new();
+        {
+            let mut channels = self.channels.lock().await;
+            let mut state = self.state.write().await;
+            for (channel_id, mut points) in measurements {
+                if points.is_empty() {
+                    continue;
+                }
+                let value_range = points
+                    .iter()
+                    .map(|p| p.value)
+                    .fold(f64::INFINITY..=f64::NEG_INFINITY, |range, value| {
+                        range.start().min(value)..=range.end().max(value)
+                    });
+
+                points.sort_by_key(|p| p.time);
+
+                let channel = channels.entry(channel_id.clone()).or_default();
+                channel.values.update(points.clone());
+                let online = match channel.online {
+                    true => None,
+                    false => AlertEvent::new(
+                        AlertKind::Online,
+                        channel_id.clone(),
+                        Some(&value_range),
+                        None,
+                    ),
+                };
+                channel.online = true;
+                channel.last_update = Some(Instant::now());
+
+                for (notifier, sub) in self.subscriptions(&channel_id).await {
+                    if let Some(event) = &online {
+                        alerts.push(Alert {
+                            notifier,
+                            target: sub.target.clone(),
+                            event: event.clone(),
+                        });
+                    }
+                    let key = self.state_key(notifier, &sub.target);
+                    let conditions = state
+                        .conditions
+                        .entry(channel_id.clone())
+                        .or_default()
+                        .entry(key)
+                        .or_default();
+                    let escalated = conditions
+                        .iter()
+                        .filter(|(_, condition)| condition.status.is_escalated())
+                        .map(|(condition, _)| *condition)
+                        .collect::<Vec<_>>();
+                    let events = sub.rule.check(
+                        &channel_id,
+                        conditions,
+                        &points,
+                        &channel.values,
+                        self.hysteresis,
+                    );
+                    for (condition, event) in events {
+                        // Escalation targets are informed about recovery too.
+                        if let (true, Some(escalation)) =
+                            (escalated.contains(&condition), &sub.rule.escalation)
+                        {
+                            for target in &escalation.targets {
+                                alerts.push(Alert {
+                                    notifier,
+                                    target: target.clone(),
+                                    event: event.repeat(true),
+                                });
+                            }
+                        }
+                        alerts.push(Alert {
+                            notifier,
+                            target: sub.target.clone(),
+                            event,
+                        });
+                    }
+                }
+            }
+            for targets in state.conditions.values_mut() {
+                for conditions in targets.values_mut() {
+                    conditions.retain(|_, condition| *condition != ConditionState::default());
+                }
+                targets.retain(|_, conditions| !conditions.is_empty());
+            }
+            state.conditions.retain(|_, targets| !targets.is_empty());
+            state.async_drop().await;
+        }
+
+        self.dispatch(alerts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use std::convert::Infallible;
+
+    fn channel() -> ChannelId {
+        ChannelId::try_from("t").unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn point(value: f64, secs: u64) -> Point {
+        Point {
+            value,
+            time: at(secs),
+        }
+    }
+
+    /// Observe range reading, returns kind of resulting event.
+    fn observe(
+        state: &mut ConditionState,
+        violated: bool,
+        recovered: bool,
+        secs: u64,
+        debounce: &Debounce,
+    ) -> Option<AlertKind> {
+        let event = state.observe(violated, recovered, at(secs), debounce, |bad| {
+            let kind = match bad {
+                true => AlertKind::OutOfRange,
+                false => AlertKind::BackToNormal,
+            };
+            AlertEvent::new(kind, channel(), Some(&(1.0..=1.0)), Some(&(0.0..=0.5)))
+        });
+        event.map(|event| event.event)
+    }
+
+    #[test]
+    fn debounce_requires_points_and_duration() {
+        let debounce = Debounce {
+            min_points: 3,
+            min_duration: 20.0,
+            realert_interval: 0.0,
+        };
+        let mut state = ConditionState::default();
+        assert_eq!(observe(&mut state, true, false, 0, &debounce), None);
+        assert_eq!(observe(&mut state, true, false, 10, &debounce), None);
+        // Enough points, but violation is too short.
+        assert_eq!(observe(&mut state, true, false, 15, &debounce), None);
+        assert_eq!(
+            state.status,
+            AlertStatus::Pending {
+                streak: 3,
+                since: at(0)
+            }
+        );
+        assert_eq!(
+            observe(&mut state, true, false, 20, &debounce),
+            Some(AlertKind::OutOfRange)
+        );
+        assert!(matches!(state.status, AlertStatus::Firing { .. }));
+
+        // Value is good, but not enough to recover.
+        assert_eq!(observe(&mut state, false, false, 30, &debounce), None);
+        assert!(matches!(state.status, AlertStatus::Firing { .. }));
+        assert_eq!(
+            observe(&mut state, false, true, 40, &debounce),
+            Some(AlertKind::BackToNormal)
+        );
+        assert_eq!(state.status, AlertStatus::Normal);
+
+        // Good reading resets pending violation.
+        assert_eq!(observe(&mut state, true, false, 50, &debounce), None);
+        assert_eq!(observe(&mut state, false, false, 55, &debounce), None);
+        assert_eq!(state.status, AlertStatus::Normal);
+    }
+
+    #[test]
+    fn realert_interval_defers_alert() {
+        let debounce = Debounce {
+            realert_interval: 100.0,
+            ..Default::default()
+        };
+        let mut state = ConditionState::default();
+        assert_eq!(
+            observe(&mut state, true, false, 0, &debounce),
+            Some(AlertKind::OutOfRange)
+        );
+        assert_eq!(
+            observe(&mut state, false, true, 10, &debounce),
+            Some(AlertKind::BackToNormal)
+        );
+        assert_eq!(observe(&mut state, true, false, 20, &debounce), None);
+        assert_eq!(observe(&mut state, true, false, 99, &debounce), None);
+        assert_eq!(
+            observe(&mut state, true, false, 100, &debounce),
+            Some(AlertKind::OutOfRange)
+        );
+    }
+
+    /// Check points against rule, returns conditions and kinds of resulting events.
+    fn check(
+        rule: &Rule,
+        states: &mut HashMap<Condition, ConditionState>,
+        history: &mut ChannelHistory,
+        points: &[Point],
+    ) -> Vec<(Condition, AlertKind)> {
+        history.update(points.iter().copied());
+        rule.check(&channel(), states, points, history, 5.0)
+            .into_iter()
+            .map(|(condition, event)| (condition, event.event))
+            .collect()
+    }
+
+    #[test]
+    fn range_recovers_with_hysteresis() {
+        let rule = Rule::default();
+        let mut states = HashMap::new();
+        let mut history = ChannelHistory::default();
+        assert_eq!(
+            check(&rule, &mut states, &mut history, &[point(85.0, 1)]),
+            [(Condition::Range, AlertKind::OutOfRange)]
+        );
+        assert_eq!(
+            check(&rule, &mut states, &mut history, &[point(78.0, 2)]),
+            []
+        );
+        assert_eq!(
+            check(&rule, &mut states, &mut history, &[point(70.0, 3)]),
+            [(Condition::Range, AlertKind::BackToNormal)]
+        );
+    }
+
+    #[test]
+    fn rate_is_checked_over_window() {
+        let rule = Rule {
+            normal_range: 0.0..=100.0,
+            rate: Some(RateRule {
+                window: 600.0,
+                max_fall: Some(10.0),
+                max_rise: None,
+            }),
+            ..Default::default()
+        };
+        let mut states = HashMap::new();
+        let mut history = ChannelHistory::default();
+        assert_eq!(
+            check(
+                &rule,
+                &mut states,
+                &mut history,
+                &[point(50.0, 0), point(45.0, 300)]
+            ),
+            []
+        );
+        let events = rule.check(&channel(), &mut states, &[], &history, 5.0);
+        assert!(events.is_empty());
+
+        history.update([point(38.0, 600)]);
+        let events = rule.check(&channel(), &mut states, &[point(38.0, 600)], &history, 5.0);
+        let [(Condition::Fall, event)] = &events[..] else {
+            panic!("Unexpected events: {events:?}");
+        };
+        assert_eq!(event.event, AlertKind::RateExceeded);
+        assert_eq!(event.change, Some(-12.0));
+        assert_eq!(event.limit, Some(10.0));
+
+        assert_eq!(
+            check(&rule, &mut states, &mut history, &[point(45.0, 1200)]),
+            [(Condition::Fall, AlertKind::RateNormal)]
+        );
+    }
+
+    #[test]
+    fn rate_event_is_not_created_by_new() {
+        assert!(AlertEvent::new(AlertKind::RateExceeded, channel(), None, None).is_none());
+        assert!(AlertEvent::new(AlertKind::OutOfRange, channel(), None, None).is_none());
+        assert!(AlertEvent::rate(AlertKind::Offline, channel(), 1.0, 60.0, 1.0).is_none());
+    }
+
+    /// Notifier with a single target `a` that records sent events.
+    struct MockNotifier {
+        rule: Rule,
+        sent: Arc<std::sync::Mutex<Vec<(String, AlertEvent)>>>,
+    }
+
+    impl Notifier for MockNotifier {
+        type Error = Infallible;
+        async fn subscriptions(&self, _channel: ChannelId) -> Vec<Subscription> {
+            vec![Subscription {
+                target: "a".to_string(),
+                rule: self.rule.clone(),
+            }]
+        }
+        async fn notify(&self, target: String, event: AlertEvent) -> Result<(), Infallible> {
+            self.sent.lock().unwrap().push((target, event));
+            Ok(())
+        }
+    }
+
+    /// Targets, kinds, reminder numbers and escalation flags of alerts.
+    fn summary<'a>(
+        alerts: impl IntoIterator<Item = (&'a String, &'a AlertEvent)>,
+    ) -> Vec<(String, AlertKind, u32, bool)> {
+        alerts
+            .into_iter()
+            .map(|(target, event)| (target.clone(), event.event, event.reminder, event.escalated))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn remind_escalate_and_acknowledge() {
+        let sent = Arc::default();
+        let notifier = MockNotifier {
+            rule: Rule {
+                remind_interval: Some(60.0),
+                escalation: Some(Escalation {
+                    timeout: 120.0,
+                    targets: vec!["b".to_string()],
+                }),
+                ..Default::default()
+            },
+            sent: Arc::clone(&sent),
+        };
+        let mut alerting = Alerting::new(
+            AlertingConfig {
+                offline_timeout: 240.0,
+                hysteresis: 5.0,
+            },
+            vec![("mock".to_string(), AnyNotifier::new(notifier))],
+            AnyStorage::new(MemStorage::default()),
+        )
+        .await;
+        let measurements = |value| {
+            Measurements::from_iter([(
+                channel(),
+                vec![Point {
+                    value,
+                    time: SystemTime::now(),
+                }],
+            )])
+        };
+        let sent_since =
+            |start: usize| summary(sent.lock().unwrap()[start..].iter().map(|(t, e)| (t, e)));
+        let reminders =
+            |alerts: Vec<Alert>| summary(alerts.iter().map(|alert| (&alert.target, &alert.event)));
+        let alert = |target: &str, kind, reminder, escalated| {
+            (target.to_string(), kind, reminder, escalated)
+        };
+
+        assert!(alerting.update(measurements(90.0)).await.is_empty());
+        assert_eq!(
+            sent_since(0),
+            [
+                alert("a", AlertKind::Online, 0, false),
+                alert("a", AlertKind::OutOfRange, 0, false)
+            ]
+        );
+
+        let now = SystemTime::now();
+        assert_eq!(reminders(alerting.reminders(now).await), []);
+        assert_eq!(
+            reminders(alerting.reminders(now + Duration::from_secs(61)).await),
+            [alert("a", AlertKind::OutOfRange, 1, false)]
+        );
+        assert_eq!(
+            reminders(alerting.reminders(now + Duration::from_secs(130)).await),
+            [
+                alert("b", AlertKind::OutOfRange, 1, true),
+                alert("a", AlertKind::OutOfRange, 2, false)
+            ]
+        );
+
+        // Only subscribed and escalation targets can acknowledge.
+        assert_eq!(alerting.acknowledge(0, "c", None).await, 0);
+        assert_eq!(alerting.acknowledge(0, "b", Some(&channel())).await, 1);
+        assert_eq!(alerting.acknowledge(0, "a", None).await, 0);
+        assert_eq!(
+            reminders(alerting.reminders(now + Duration::from_secs(1000)).await),
+            []
+        );
+
+        // Escalation target is informed about recovery too.
+        assert!(alerting.update(measurements(50.0)).await.is_empty());
+        assert_eq!(
+            sent_since(2),
+            [
+                alert("b", AlertKind::BackToNormal, 0, true),
+                alert("a", AlertKind::BackToNormal, 0, false)
+            ]
+        );
+    }
+}
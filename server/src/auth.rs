@@ -0,0 +1,382 @@
+use crate::config::AuthConfig;
+use rtherm_common::{signature, ChannelId, Measurements};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// Credentials provided by client.
+#[derive(Clone, Debug)]
+pub enum Credentials<'a> {
+    Token(&'a str),
+    /// Body signed with HMAC key.
+    Signed {
+        key_id: &'a str,
+        timestamp: u64,
+        nonce: &'a str,
+        signature: &'a str,
+        body: &'a [u8],
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    /// Identifier of client that does not reveal the token
+    id: String,
+    channel_prefix: String,
+}
+
+#[derive(Clone, Debug)]
+struct Key {
+    key: Vec<u8>,
+    channel_prefix: String,
+}
+
+/// Client whose credentials are checked.
+#[derive(Clone, Debug)]
+pub struct Client<'a> {
+    /// Identifier of client, e.g. to distinguish batches of different clients
+    pub id: String,
+    channel_prefix: &'a str,
+    /// Key id and nonce of signed request
+    nonce: Option<(String, String)>,
+}
+
+/// Access control for clients providing measurements.
+#[derive(Debug)]
+pub struct Auth {
+    tokens: HashMap<String, Token>,
+    keys: HashMap<String, Key>,
+    /// Max difference between signature timestamp and server time
+    replay_window: Duration,
+    /// Nonces of authorized signed requests seen within replay window
+    nonces: Mutex<HashMap<(String, String), SystemTime>>,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            tokens: config
+                .tokens
+                .into_iter()
+                .map(|t| {
+                    let token = Token {
+                        id: format!("token:{}", fingerprint(&t.token)),
+                        channel_prefix: t.channel_prefix,
+                    };
+                    (t.token, token)
+                })
+                .collect(),
+            keys: config
+                .keys
+                .into_iter()
+                .map(|k| {
+                    let key = Key {
+                        key: k.key.into_bytes(),
+                        channel_prefix: k.channel_prefix,
+                    };
+                    (k.id, key)
+                })
+                .collect(),
+            replay_window: Duration::from_secs_f64(config.replay_window),
+            nonces: Mutex::default(),
+        }
+    }
+
+    /// Check `credentials` of client.
+    ///
+    /// Client must be authorized for measurements it provides separately.
+    pub fn authenticate(&self, credentials: Option<Credentials>) -> Result<Client<'_>, AuthError> {
+        match credentials.ok_or(AuthError::MissingCredentials)? {
+            Credentials::Token(token) => {
+                let token = self.tokens.get(token).ok_or(AuthError::UnknownToken)?;
+                Ok(Client {
+                    id: token.id.clone(),
+                    channel_prefix: &token.channel_prefix,
+                    nonce: None,
+                })
+            }
+            Credentials::Signed {
+                key_id,
+                timestamp,
+                nonce,
+                signature,
+                body,
+            } => {
+                let key = self.keys.get(key_id).ok_or(AuthError::UnknownKey)?;
+                if !signature::verify(&key.key, timestamp, nonce, body, signature) {
+                    return Err(AuthError::BadSignature);
+                }
+                self.check_replay(key_id, timestamp, nonce)?;
+                Ok(Client {
+                    id: format!("key:{key_id}"),
+                    channel_prefix: &key.channel_prefix,
+                    nonce: Some((key_id.to_string(), nonce.to_string())),
+                })
+            }
+        }
+    }
+
+    /// Check that `client` is allowed to write to all channels of `meas`.
+    ///
+    /// Nonce of signed request is recorded only when request is authorized.
+    pub fn authorize(&self, client: &Client, meas: &Measurements) -> Result<(), AuthError> {
+        if let Some(id) = meas
+            .keys()
+            .find(|id| !id.starts_with(client.channel_prefix))
+        {
+            return Err(AuthError::ForbiddenChannel(id.clone()));
+        }
+        if let Some(nonce) = &client.nonce {
+            let mut nonces = self.nonces.lock().unwrap();
+            if nonces.insert(nonce.clone(), SystemTime::now()).is_some() {
+                return Err(AuthError::ReplayedNonce);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_replay(&self, key_id: &str, timestamp: u64, nonce: &str) -> Result<(), AuthError> {
+        let now = SystemTime::now();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp);
+        let skew = match now.duration_since(time) {
+            Ok(dur) => dur,
+            Err(e) => e.duration(),
+        };
+        if skew > self.replay_window {
+            return Err(AuthError::StaleTimestamp);
+        }
+
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, seen| {
+            now.duration_since(*seen).unwrap_or_default() <= 2 * self.replay_window
+        });
+        if nonces.contains_key(&(key_id.to_string(), nonce.to_string())) {
+            return Err(AuthError::ReplayedNonce);
+        }
+        Ok(())
+    }
+}
+
+/// Short hash of secret that identifies it without revealing.
+fn fingerprint(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub enum AuthError {
+    MissingCredentials,
+    UnknownToken,
+    UnknownKey,
+    BadSignature,
+    StaleTimestamp,
+    ReplayedNonce,
+    ForbiddenChannel(ChannelId),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "Neither token nor signature provided"),
+            AuthError::UnknownToken => write!(f, "Unknown token"),
+            AuthError::UnknownKey => write!(f, "Unknown key id"),
+            AuthError::BadSignature => write!(f, "Bad signature"),
+            AuthError::StaleTimestamp => write!(f, "Timestamp is outside of replay window"),
+            AuthError::ReplayedNonce => write!(f, "Nonce has already been used"),
+            AuthError::ForbiddenChannel(id) => write!(f, "Writing to channel {id} is not allowed"),
+        }
+    }
+}
+impl Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{KeyConfig, TokenConfig};
+    use rtherm_common::Point;
+
+    fn auth() -> Auth {
+        Auth::new(AuthConfig {
+            tokens: vec![TokenConfig {
+                token: "secret".to_string(),
+                channel_prefix: "house1_".to_string(),
+            }],
+            keys: vec![KeyConfig {
+                id: "pi".to_string(),
+                key: "key".to_string(),
+                channel_prefix: "house2_".to_string(),
+            }],
+            replay_window: 300.0,
+        })
+    }
+
+    fn meas(channels: &[&str]) -> Measurements {
+        channels
+            .iter()
+            .map(|id| {
+                let point = Point {
+                    value: 1.0,
+                    time: SystemTime::now(),
+                };
+                (ChannelId::try_from(*id).unwrap(), vec![point])
+            })
+            .collect()
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn token_is_restricted_to_channel_prefix() {
+        let auth = auth();
+        let client = auth
+            .authenticate(Some(Credentials::Token("secret")))
+            .unwrap();
+        assert!(client.id.starts_with("token:"));
+        assert!(!client.id.contains("secret"));
+
+        auth.authorize(&client, &meas(&["house1_boiler", "house1_room"]))
+            .unwrap();
+        assert!(matches!(
+            auth.authorize(&client, &meas(&["house1_boiler", "house2_boiler"])),
+            Err(AuthError::ForbiddenChannel(id)) if *id == *"house2_boiler"
+        ));
+    }
+
+    #[test]
+    fn missing_or_unknown_token_is_rejected() {
+        let auth = auth();
+        assert!(matches!(
+            auth.authenticate(None),
+            Err(AuthError::MissingCredentials)
+        ));
+        assert!(matches!(
+            auth.authenticate(Some(Credentials::Token("other"))),
+            Err(AuthError::UnknownToken)
+        ));
+        // Key id is not a token.
+        assert!(matches!(
+            auth.authenticate(Some(Credentials::Token("pi"))),
+            Err(AuthError::UnknownToken)
+        ));
+    }
+
+    #[test]
+    fn nonce_of_forbidden_request_is_not_recorded() {
+        let auth = auth();
+        let timestamp = now_secs();
+        let body = b"forbidden";
+        let signature = signature::sign(b"key", timestamp, "n1", body);
+        let credentials = Credentials::Signed {
+            key_id: "pi",
+            timestamp,
+            nonce: "n1",
+            signature: &signature,
+            body,
+        };
+
+        let client = auth.authenticate(Some(credentials.clone())).unwrap();
+        assert!(matches!(
+            auth.authorize(&client, &meas(&["house1_boiler"])),
+            Err(AuthError::ForbiddenChannel(..))
+        ));
+        assert!(auth.nonces.lock().unwrap().is_empty());
+
+        let client = auth.authenticate(Some(credentials)).unwrap();
+        auth.authorize(&client, &meas(&["house2_boiler"])).unwrap();
+        assert_eq!(auth.nonces.lock().unwrap().len(), 1);
+    }
+
+    fn signed<'a>(
+        timestamp: u64,
+        nonce: &'a str,
+        body: &'a [u8],
+        signature: &'a str,
+    ) -> Credentials<'a> {
+        Credentials::Signed {
+            key_id: "pi",
+            timestamp,
+            nonce,
+            signature,
+            body,
+        }
+    }
+
+    #[test]
+    fn valid_signature_is_accepted() {
+        let auth = auth();
+        let timestamp = now_secs();
+        let signature = signature::sign(b"key", timestamp, "n1", b"body");
+        let client = auth
+            .authenticate(Some(signed(timestamp, "n1", b"body", &signature)))
+            .unwrap();
+        assert_eq!(client.id, "key:pi");
+        auth.authorize(&client, &meas(&["house2_boiler"])).unwrap();
+    }
+
+    #[test]
+    fn tampered_request_is_rejected() {
+        let auth = auth();
+        let timestamp = now_secs();
+        let signature = signature::sign(b"key", timestamp, "n1", b"body");
+        for credentials in [
+            signed(timestamp, "n1", b"tampered", &signature),
+            signed(timestamp + 1, "n1", b"body", &signature),
+            signed(timestamp, "n2", b"body", &signature),
+            signed(timestamp, "n1", b"body", "not hex"),
+        ] {
+            assert!(matches!(
+                auth.authenticate(Some(credentials)),
+                Err(AuthError::BadSignature)
+            ));
+        }
+        let other = signature::sign(b"other key", timestamp, "n1", b"body");
+        assert!(matches!(
+            auth.authenticate(Some(signed(timestamp, "n1", b"body", &other))),
+            Err(AuthError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn timestamp_outside_of_window_is_rejected() {
+        let auth = auth();
+        for timestamp in [now_secs() - 301, now_secs() + 301] {
+            let signature = signature::sign(b"key", timestamp, "n1", b"body");
+            assert!(matches!(
+                auth.authenticate(Some(signed(timestamp, "n1", b"body", &signature))),
+                Err(AuthError::StaleTimestamp)
+            ));
+        }
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let auth = auth();
+        let timestamp = now_secs();
+        let signature = signature::sign(b"key", timestamp, "n1", b"body");
+        let credentials = signed(timestamp, "n1", b"body", &signature);
+        let first = auth.authenticate(Some(credentials.clone())).unwrap();
+        // Both requests are authenticated before either of them is authorized.
+        let second = auth.authenticate(Some(credentials.clone())).unwrap();
+        auth.authorize(&first, &meas(&["house2_boiler"])).unwrap();
+        assert!(matches!(
+            auth.authorize(&second, &meas(&["house2_boiler"])),
+            Err(AuthError::ReplayedNonce)
+        ));
+        assert!(matches!(
+            auth.authenticate(Some(credentials)),
+            Err(AuthError::ReplayedNonce)
+        ));
+    }
+}
@@ -0,0 +1,37 @@
+use rtherm_common::BatchId;
+use std::collections::{HashSet, VecDeque};
+
+/// Batch of particular client, batches of different clients do not clash.
+///
+/// Client is `None` when authentication is disabled.
+pub type BatchKey = (Option<String>, BatchId);
+
+/// Set of recently applied batches.
+///
+/// Only the last [`RecentBatches::MAX_LEN`] batches are remembered.
+#[derive(Clone, Default, Debug)]
+pub struct RecentBatches {
+    set: HashSet<BatchKey>,
+    /// Batches in order of insertion
+    queue: VecDeque<BatchKey>,
+}
+
+impl RecentBatches {
+    const MAX_LEN: usize = 4096;
+
+    pub fn contains(&self, key: &BatchKey) -> bool {
+        self.set.contains(key)
+    }
+
+    pub fn insert(&mut self, key: BatchKey) {
+        if !self.set.insert(key.clone()) {
+            return;
+        }
+        self.queue.push_back(key);
+        if self.queue.len() > Self::MAX_LEN {
+            if let Some(old) = self.queue.pop_front() {
+                self.set.remove(&old);
+            }
+        }
+    }
+}
@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
+use crate::alerting::{Debounce, RateRule};
 use serde::Deserialize;
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 use tokio::fs;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -22,15 +23,277 @@ pub struct SqliteConfig {
     pub path: String,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// Raw points older than this are deleted, in seconds.
+    ///
+    /// Hourly and daily rollups are kept forever.
+    /// If not set then raw points are kept forever too.
+    pub raw_max_age: Option<f64>,
+    /// Period of updating rollups and deleting old raw points, in seconds.
+    #[serde(default = "RetentionConfig::default_rollup_period")]
+    pub rollup_period: f64,
+}
+
+impl RetentionConfig {
+    fn default_rollup_period() -> f64 {
+        600.0
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            raw_max_age: None,
+            rollup_period: Self::default_rollup_period(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PoolConfig {
+    /// Max number of connections to each database.
+    #[serde(default = "PoolConfig::default_max_connections")]
+    pub max_connections: u32,
+    /// Max time to wait for a connection, in seconds.
+    #[serde(default = "PoolConfig::default_acquire_timeout")]
+    pub acquire_timeout: f64,
+    /// Max time to wait for database at startup, in seconds.
+    ///
+    /// After that server starts anyway and keeps reconnecting in background.
+    #[serde(default = "PoolConfig::default_startup_timeout")]
+    pub startup_timeout: f64,
+}
+
+impl PoolConfig {
+    fn default_max_connections() -> u32 {
+        4
+    }
+    fn default_acquire_timeout() -> f64 {
+        10.0
+    }
+    fn default_startup_timeout() -> f64 {
+        60.0
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: Self::default_max_connections(),
+            acquire_timeout: Self::default_acquire_timeout(),
+            startup_timeout: Self::default_startup_timeout(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct DbConfig {
     pub postgres: Option<PostgresConfig>,
     pub sqlite: Option<SqliteConfig>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct TelegramConfig {
     pub token: String,
+    /// Interval of reminders about alerts until they are resolved or acknowledged, in seconds.
+    pub remind_interval: Option<f64>,
+    /// Escalation of alerts that are not acknowledged in time.
+    pub escalation: Option<TelegramEscalationConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TelegramEscalationConfig {
+    /// Time to wait for acknowledgement of alert, in seconds.
+    pub timeout: f64,
+    /// Chats to alert additionally.
+    pub chats: Vec<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct InfluxConfig {
+    /// Base URL of InfluxDB-compatible server, e.g. `http://localhost:8086`
+    pub url: String,
+    pub bucket: String,
+    pub org: Option<String>,
+    /// API token, sent in `Authorization` header
+    pub token: Option<String>,
+    /// Name of measurement in line protocol, channel id is written as `channel` tag.
+    #[serde(default = "InfluxConfig::default_measurement")]
+    pub measurement: String,
+    /// Max number of points in a single write request.
+    #[serde(default = "InfluxConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Number of retries of a failed write request before points are dropped.
+    #[serde(default = "InfluxConfig::default_max_retries")]
+    pub max_retries: u32,
+    /// Timeout of write request, in seconds.
+    #[serde(default = "InfluxConfig::default_timeout")]
+    pub timeout: f64,
+}
+
+impl InfluxConfig {
+    fn default_measurement() -> String {
+        "temperature".to_string()
+    }
+    fn default_batch_size() -> usize {
+        5000
+    }
+    fn default_max_retries() -> u32 {
+        5
+    }
+    fn default_timeout() -> f64 {
+        10.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Values are published to `<topic_prefix>/<channel>/state`.
+    #[serde(default = "MqttConfig::default_topic_prefix")]
+    pub topic_prefix: String,
+    /// Quality of service of published messages: 0, 1 or 2.
+    #[serde(default = "MqttConfig::default_qos")]
+    pub qos: u8,
+    /// Whether broker should keep the last value of each channel.
+    #[serde(default = "MqttConfig::default_retain")]
+    pub retain: bool,
+    /// Publish Home Assistant discovery configs.
+    #[serde(default = "MqttConfig::default_discovery")]
+    pub discovery: bool,
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Keep alive interval, in seconds.
+    #[serde(default = "MqttConfig::default_keep_alive")]
+    pub keep_alive: f64,
+}
+
+impl MqttConfig {
+    fn default_port() -> u16 {
+        1883
+    }
+    fn default_client_id() -> String {
+        "rtherm".to_string()
+    }
+    fn default_topic_prefix() -> String {
+        "rtherm".to_string()
+    }
+    fn default_qos() -> u8 {
+        1
+    }
+    fn default_retain() -> bool {
+        true
+    }
+    fn default_discovery() -> bool {
+        true
+    }
+    fn default_discovery_prefix() -> String {
+        "homeassistant".to_string()
+    }
+    fn default_keep_alive() -> f64 {
+        30.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookEndpointConfig {
+    pub url: String,
+    /// Channels to alert about, `*` at the end matches any suffix.
+    ///
+    /// If empty then all channels are matched.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Range of good values, `[min, max]`.
+    #[serde(default = "WebhookEndpointConfig::default_normal_range")]
+    pub normal_range: [f64; 2],
+    /// Limits of value change over time window.
+    pub rate: Option<RateRule>,
+    /// Requirements for violation to be alerted.
+    #[serde(default)]
+    pub debounce: Debounce,
+    /// Interval of reminders while violation lasts, in seconds.
+    pub remind_interval: Option<f64>,
+    /// Request body with `{{event}}`, `{{channel}}`, `{{message}}`, `{{min}}`, `{{max}}`,
+    /// `{{normal_min}}`, `{{normal_max}}`, `{{change}}`, `{{window}}`, `{{limit}}`,
+    /// `{{time}}`, `{{reminder}}` and `{{escalated}}` placeholders.
+    ///
+    /// Values are escaped as JSON strings if `content_type` is JSON.
+    /// If not set then event is sent as JSON.
+    pub template: Option<String>,
+    /// Content type of templated body.
+    #[serde(default = "WebhookEndpointConfig::default_content_type")]
+    pub content_type: String,
+    /// Additional HTTP headers, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl WebhookEndpointConfig {
+    fn default_normal_range() -> [f64; 2] {
+        [30.0, 80.0]
+    }
+    fn default_content_type() -> String {
+        "application/json".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// Number of retries of a failed request before event is dropped.
+    #[serde(default = "WebhookConfig::default_max_retries")]
+    pub max_retries: u32,
+    /// Timeout of request, in seconds.
+    #[serde(default = "WebhookConfig::default_timeout")]
+    pub timeout: f64,
+    pub endpoints: Vec<WebhookEndpointConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlertingConfig {
+    /// Time to assume that channel is offline, in seconds.
+    #[serde(default = "AlertingConfig::default_offline_timeout")]
+    pub offline_timeout: f64,
+    /// Offset from normal range bound when value becomes normal again.
+    #[serde(default = "AlertingConfig::default_hysteresis")]
+    pub hysteresis: f64,
+}
+
+impl AlertingConfig {
+    fn default_offline_timeout() -> f64 {
+        240.0
+    }
+    fn default_hysteresis() -> f64 {
+        5.0
+    }
+}
+
+impl Default for AlertingConfig {
+    fn default() -> Self {
+        Self {
+            offline_timeout: Self::default_offline_timeout(),
+            hysteresis: Self::default_hysteresis(),
+        }
+    }
+}
+
+impl WebhookConfig {
+    fn default_max_retries() -> u32 {
+        5
+    }
+    fn default_timeout() -> f64 {
+        10.0
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Deserialize)]
@@ -49,13 +312,98 @@ pub struct StorageConfig {
     pub path: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenConfig {
+    pub token: String,
+    /// Client is allowed to write only to channels starting with this prefix.
+    #[serde(default)]
+    pub channel_prefix: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyConfig {
+    pub id: String,
+    /// Shared secret used to sign requests with HMAC
+    pub key: String,
+    /// Client is allowed to write only to channels starting with this prefix.
+    #[serde(default)]
+    pub channel_prefix: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    /// Max difference between time of signed request and server time in seconds
+    #[serde(default = "AuthConfig::default_replay_window")]
+    pub replay_window: f64,
+}
+
+impl AuthConfig {
+    fn default_replay_window() -> f64 {
+        300.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct QueueConfig {
+    /// Max number of requests waiting to be processed by each recepient.
+    ///
+    /// Requests are rejected when queue of any recepient is full.
+    #[serde(default = "QueueConfig::default_capacity")]
+    pub capacity: usize,
+}
+
+impl QueueConfig {
+    fn default_capacity() -> usize {
+        1024
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: Self::default_capacity(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WalConfig {
+    /// Directory of write-ahead log
+    pub path: String,
+    /// Size of log segment in bytes, segments are removed when processed by all recepients.
+    #[serde(default = "WalConfig::default_segment_size")]
+    pub segment_size: u64,
+}
+
+impl WalConfig {
+    fn default_segment_size() -> u64 {
+        16 * 1024 * 1024
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub http: HttpConfig,
     pub db: Option<DbConfig>,
     pub telegram: Option<TelegramConfig>,
+    pub influxdb: Option<InfluxConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub webhook: Option<WebhookConfig>,
+    /// Rules evaluation shared by Telegram and webhooks.
+    #[serde(default)]
+    pub alerting: AlertingConfig,
     #[serde(default)]
     pub storage: StorageConfig,
+    /// If not set then anyone can provide measurements.
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// If not set then accepted requests that are not processed yet are lost on restart.
+    pub wal: Option<WalConfig>,
 }
 
 impl Config {
@@ -1,119 +1,840 @@
-use chrono::{DateTime, Local};
-use rtherm_common::Measurements;
+use chrono::{DateTime, Utc};
+use rtherm_common::{ChannelId, Measurements, Point};
+use serde::Serialize;
 use sqlx::{
-    ColumnIndex, Connection, Database, Decode, Encode, Error, Executor, IntoArguments, Row, Type,
-    TypeInfo,
+    ColumnIndex, Database, Decode, Encode, Error, Executor, IntoArguments, Pool, Row, Type,
+};
+use std::{
+    collections::HashMap,
+    error,
+    fmt::{self, Display},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{watch, OnceCell},
+    task::spawn,
+    time::sleep,
 };
 
-use crate::{recepient::Recepient, storage::Storage};
+use crate::{
+    config::RetentionConfig,
+    history::{Bucket, History, PointsQuery},
+    migrations::migrate,
+    recepient::Recepient,
+    retry::Backoff,
+    storage::Storage,
+};
 
-pub struct Db<C: Connection>
-where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
-{
-    client: C,
+/// Database-specific SQL.
+pub trait Dialect: Database {
+    /// Expression that converts timestamp `column` to seconds since Unix epoch as float.
+    fn epoch_seconds(column: &str) -> String;
+    /// Integer index of bucket of size `width` seconds that contains `seconds`.
+    fn bucket_index(seconds: &str, width: &str) -> String;
+    /// Smaller of two values.
+    fn least(a: &str, b: &str) -> String;
+    /// Greater of two values.
+    fn greatest(a: &str, b: &str) -> String;
+    /// Timestamp `column` converted to UTC in the same form as bound `DateTime<Utc>`.
+    fn utc_timestamp(column: &str) -> String;
 }
 
-impl<C: Connection> Db<C>
-where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
-{
-    pub async fn new(mut client: C) -> Result<Self, Error> {
-        sqlx::query("CREATE TABLE IF NOT EXISTS Measurements (channel_id VARCHAR, value FLOAT, time TIMESTAMP)").execute(&mut client).await?;
-        Ok(Self { client })
+#[cfg(feature = "postgres")]
+impl Dialect for sqlx::Postgres {
+    fn epoch_seconds(column: &str) -> String {
+        format!("EXTRACT(EPOCH FROM {column}::TIMESTAMPTZ)::FLOAT8")
+    }
+    fn bucket_index(seconds: &str, width: &str) -> String {
+        format!("FLOOR({seconds} / {width})::BIGINT")
+    }
+    fn least(a: &str, b: &str) -> String {
+        format!("LEAST({a}, {b})")
+    }
+    fn greatest(a: &str, b: &str) -> String {
+        format!("GREATEST({a}, {b})")
+    }
+    fn utc_timestamp(column: &str) -> String {
+        // Time zone is not stored in TIMESTAMP.
+        column.to_string()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Dialect for sqlx::Sqlite {
+    fn epoch_seconds(column: &str) -> String {
+        format!("ROUND((julianday({column}) - 2440587.5) * 86400.0, 3)")
+    }
+    fn bucket_index(seconds: &str, width: &str) -> String {
+        // Cast truncates towards zero, but timestamps before epoch are not expected
+        format!("CAST({seconds} / {width} AS INTEGER)")
+    }
+    fn least(a: &str, b: &str) -> String {
+        format!("MIN({a}, {b})")
+    }
+    fn greatest(a: &str, b: &str) -> String {
+        format!("MAX({a}, {b})")
+    }
+    fn utc_timestamp(column: &str) -> String {
+        // Timestamps are RFC 3339 text compared as strings, so offset must be the same.
+        // Offset is whole minutes, so fraction of second is kept as is.
+        format!(
+            "strftime('%Y-%m-%dT%H:%M:%S', {column}) \
+            || substr({column}, 20, length({column}) - 25) || '+00:00'"
+        )
+    }
+}
+
+fn from_epoch_seconds(secs: f64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::try_from_secs_f64(secs).unwrap_or_default()
+}
+
+/// Table with points aggregated over fixed time intervals.
+#[derive(Clone, Copy, Debug)]
+struct Rollup {
+    table: &'static str,
+    /// Interval width in seconds
+    width: u64,
+}
+
+const ROLLUPS: [Rollup; 2] = [
+    Rollup {
+        table: "MeasurementsHourly",
+        width: 60 * 60,
+    },
+    Rollup {
+        table: "MeasurementsDaily",
+        width: 24 * 60 * 60,
+    },
+];
+
+/// Raw points are deleted by whole intervals of the largest rollup width,
+/// so that each rollup interval is computed either from all its raw points or from none.
+const RETENTION_ALIGN: u64 = 24 * 60 * 60;
+
+/// Max number of rows in a single insert statement.
+///
+/// Keeps number of bound parameters below SQLite limit.
+const INSERT_CHUNK_LEN: usize = 256;
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn align_down(time: SystemTime, width: u64) -> SystemTime {
+    let secs = epoch_secs(time);
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs - secs % width)
+}
+
+/// Aggregated values of points within rollup interval.
+#[derive(Clone, Copy, Debug)]
+struct Aggregate {
+    count: i64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregate {
+    fn new(value: f64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// Database availability as reported by health check.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Health {
+    /// Database was not reached yet
+    Connecting,
+    Available,
+    Unavailable {
+        error: String,
+    },
+}
+
+impl Health {
+    pub fn is_available(&self) -> bool {
+        matches!(self, Health::Available)
+    }
+}
+
+/// Period of health check while database is available.
+const HEALTH_CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum DbError {
+    Sql(Error),
+    /// Database was migrated by newer version of server.
+    UnsupportedSchema {
+        found: i64,
+        latest: i64,
+    },
+}
+
+impl From<Error> for DbError {
+    fn from(err: Error) -> Self {
+        DbError::Sql(err)
+    }
+}
+
+impl DbError {
+    /// Error is caused by lost connection rather than by query itself.
+    fn is_connection(&self) -> bool {
+        matches!(
+            self,
+            DbError::Sql(
+                Error::Io(_)
+                    | Error::Tls(_)
+                    | Error::Protocol(_)
+                    | Error::PoolTimedOut
+                    | Error::PoolClosed
+                    | Error::WorkerCrashed
+            )
+        )
     }
 }
 
-impl<C: Connection> Recepient for Db<C>
+impl Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sql(err) => write!(f, "{err}"),
+            DbError::UnsupportedSchema { found, latest } => write!(
+                f,
+                "Database schema version {found} is newer than the latest supported version {latest}"
+            ),
+        }
+    }
+}
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::Sql(err) => Some(err),
+            DbError::UnsupportedSchema { .. } => None,
+        }
+    }
+}
+
+/// Database connection pool shared between recepient, history queries and storage.
+///
+/// Connections are established lazily and re-established after failures,
+/// schema is migrated on first successful connection.
+pub struct Db<DB: Database> {
+    pool: Pool<DB>,
+    /// Set when schema is migrated.
+    ready: Arc<OnceCell<()>>,
+    health: Arc<watch::Sender<Health>>,
+    /// Max age of raw points
+    raw_max_age: Option<Duration>,
+}
+
+impl<DB: Database> Clone for Db<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            ready: self.ready.clone(),
+            health: self.health.clone(),
+            raw_max_age: self.raw_max_age,
+        }
+    }
+}
+
+impl<DB: Dialect> Db<DB>
 where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
 
-    for<'q> String: Type<C::Database> + Encode<'q, C::Database>,
-    for<'q> f64: Type<C::Database> + Encode<'q, C::Database>,
-    for<'q> DateTime<Local>: Type<C::Database> + Encode<'q, C::Database>,
+    usize: ColumnIndex<DB::Row>,
+    for<'q> String: Type<DB> + Encode<'q, DB>,
+    for<'q> i64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> f64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> DateTime<Utc>: Type<DB> + Encode<'q, DB>,
+    for<'q> Vec<u8>: Type<DB>,
 {
-    type Error = Error;
+    pub fn new(pool: Pool<DB>, config: &RetentionConfig) -> Self {
+        Self {
+            pool,
+            ready: Arc::new(OnceCell::new()),
+            health: Arc::new(watch::Sender::new(Health::Connecting)),
+            raw_max_age: config.raw_max_age.map(Duration::from_secs_f64),
+        }
+    }
 
-    async fn update(&mut self, meas: Measurements) -> Vec<Self::Error> {
-        // TODO: Use bulk insert
-        let mut errors = Vec::new();
-        for (channel_id, points) in meas {
-            for p in points {
-                if let Err(err) = sqlx::query::<C::Database>(
-                    "INSERT INTO Measurements (channel_id, value, time) VALUES ($1, $2, $3)",
+    /// Subscribe to changes of database health.
+    pub fn health(&self) -> watch::Receiver<Health> {
+        self.health.subscribe()
+    }
+
+    fn set_health(&self, health: Health) {
+        match (&*self.health.borrow(), &health) {
+            (Health::Available, Health::Available)
+            | (Health::Unavailable { .. }, Health::Unavailable { .. }) => (),
+            (_, Health::Available) => log::info!("Database is available"),
+            (_, Health::Unavailable { error }) => log::warn!("Database is unavailable: {error}"),
+            (_, Health::Connecting) => (),
+        }
+        self.health.send_replace(health);
+    }
+
+    /// Mark database unavailable if `err` is caused by lost connection.
+    ///
+    /// Health monitor will detect when it is back.
+    fn check_error<T>(&self, result: Result<T, DbError>) -> Result<T, DbError> {
+        if let Err(err) = &result {
+            if err.is_connection() {
+                self.set_health(Health::Unavailable {
+                    error: err.to_string(),
+                });
+            }
+        }
+        result
+    }
+
+    /// Migrate schema if it is not done yet.
+    async fn ensure_ready(&self) -> Result<(), DbError> {
+        self.ready
+            .get_or_try_init(|| async {
+                let mut conn = self.pool.acquire().await?;
+                migrate(&mut conn).await
+            })
+            .await
+            .map(|_| ())
+    }
+
+    async fn check_health(&self) -> Result<(), DbError> {
+        self.ensure_ready().await?;
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Wait until database becomes available.
+    ///
+    /// Returns `false` if it is still unavailable after `timeout`.
+    pub async fn wait_available(&self, timeout: Duration) -> bool {
+        let mut health = self.health();
+        tokio::time::timeout(timeout, health.wait_for(Health::is_available))
+            .await
+            .is_ok_and(|r| r.is_ok())
+    }
+
+    /// Raw points older than this time are not stored.
+    fn retention_cutoff(&self) -> Option<SystemTime> {
+        self.raw_max_age.map(|max_age| {
+            align_down(
+                SystemTime::now()
+                    .checked_sub(max_age)
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+                RETENTION_ALIGN,
+            )
+        })
+    }
+
+    /// Recompute rollups for intervals that have new raw points.
+    ///
+    /// Earliest time of raw points that are not included into rollups yet is kept in `RollupsDirty`,
+    /// so that it survives restarts.
+    async fn update_rollups(conn: &mut DB::Connection) -> Result<(), DbError> {
+        let dirty = sqlx::query::<DB>(&format!(
+            "SELECT {}, version FROM RollupsDirty",
+            DB::epoch_seconds("dirty_from")
+        ))
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(row) = dirty {
+            let time = from_epoch_seconds(row.try_get(0)?);
+            let version: i64 = row.try_get(1)?;
+            for rollup in ROLLUPS {
+                sqlx::query::<DB>(&format!(
+                    "INSERT INTO {table} (channel_id, time, count, mean, min, max) \
+                    SELECT channel_id, {bucket} * {width} AS bucket, COUNT(*), AVG(value), MIN(value), MAX(value) \
+                    FROM Measurements WHERE time >= $1 GROUP BY channel_id, bucket \
+                    ON CONFLICT (channel_id, time) DO UPDATE SET \
+                    count = excluded.count, mean = excluded.mean, min = excluded.min, max = excluded.max",
+                    table = rollup.table,
+                    width = rollup.width,
+                    bucket = DB::bucket_index(&DB::epoch_seconds("time"), &rollup.width.to_string()),
+                ))
+                .bind(DateTime::<Utc>::from(align_down(time, rollup.width)))
+                .execute(&mut *conn)
+                .await?;
+            }
+            // Points written meanwhile are left dirty.
+            sqlx::query::<DB>("DELETE FROM RollupsDirty WHERE version = $1")
+                .bind(version)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Update rollups and delete outdated raw points.
+    pub async fn rollup(&self) -> Result<(), DbError> {
+        let result = async {
+            self.ensure_ready().await?;
+            let cutoff = self.retention_cutoff();
+            let mut tx = self.pool.begin().await?;
+            Self::update_rollups(&mut tx).await?;
+            if let Some(cutoff) = cutoff {
+                // Points written meanwhile are kept until they are rolled up.
+                sqlx::query::<DB>(
+                    "DELETE FROM Measurements WHERE time < $1 \
+                    AND NOT EXISTS (SELECT 1 FROM RollupsDirty WHERE dirty_from <= Measurements.time)",
                 )
+                .bind(DateTime::<Utc>::from(cutoff))
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        }
+        .await;
+        self.check_error(result)
+    }
+
+    /// Aggregate rollup intervals into buckets of `width` that is multiple of rollup width.
+    ///
+    /// Rollups are updated by [`Db::rollup`], so the latest raw points could be missing.
+    async fn rollup_buckets(
+        &self,
+        rollup: &Rollup,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> Result<Vec<Bucket>, DbError> {
+        let mut conditions = String::new();
+        let mut bounds = Vec::new();
+        for (bound, op) in [(query.from, ">="), (query.to, "<=")] {
+            if let Some(time) = bound {
+                conditions += &format!(" AND time {op} ${}", 3 + bounds.len());
+                bounds.push(epoch_secs(time) as i64);
+            }
+        }
+        let sql = format!(
+            "SELECT {} AS bucket, CAST(SUM(count) AS BIGINT), SUM(mean * count) / SUM(count), MIN(min), MAX(max) \
+            FROM {} WHERE channel_id = $1{} \
+            GROUP BY bucket ORDER BY bucket LIMIT ${}",
+            DB::bucket_index("time", "$2"),
+            rollup.table,
+            conditions,
+            bounds.len() + 3,
+        );
+
+        let mut q = sqlx::query::<DB>(&sql)
+            .bind(channel.to_string())
+            .bind(width.as_secs_f64());
+        for time in bounds {
+            q = q.bind(time);
+        }
+        let rows = q
+            .bind(i64::try_from(query.limit).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| {
+                let index: i64 = row.try_get(0)?;
+                let count: i64 = row.try_get(1)?;
+                Ok(Bucket {
+                    time: index as f64 * width.as_secs_f64(),
+                    count: count as usize,
+                    mean: row.try_get(2)?,
+                    min: row.try_get(3)?,
+                    max: row.try_get(4)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Merge points that are older than retention cutoff directly into rollups.
+    async fn merge_into_rollups(
+        conn: &mut DB::Connection,
+        meas: &Measurements,
+    ) -> Result<(), Error> {
+        for rollup in ROLLUPS {
+            let mut aggregates = HashMap::<(&ChannelId, u64), Aggregate>::new();
+            for (channel_id, points) in meas {
+                for p in points {
+                    let time = epoch_secs(align_down(p.time, rollup.width));
+                    aggregates
+                        .entry((channel_id, time))
+                        .and_modify(|a| a.add(p.value))
+                        .or_insert_with(|| Aggregate::new(p.value));
+                }
+            }
+            for ((channel_id, time), a) in aggregates {
+                sqlx::query::<DB>(&format!(
+                    "INSERT INTO {table} (channel_id, time, count, mean, min, max) \
+                    VALUES ($1, $2, $3, $4, $5, $6) \
+                    ON CONFLICT (channel_id, time) DO UPDATE SET \
+                    count = {table}.count + excluded.count, \
+                    mean = ({table}.mean * {table}.count + excluded.mean * excluded.count) \
+                    / ({table}.count + excluded.count), \
+                    min = {min}, max = {max}",
+                    table = rollup.table,
+                    min = DB::least(&format!("{}.min", rollup.table), "excluded.min"),
+                    max = DB::greatest(&format!("{}.max", rollup.table), "excluded.max"),
+                ))
+                .bind(channel_id.to_string())
+                .bind(time as i64)
+                .bind(a.count)
+                .bind(a.sum / a.count as f64)
+                .bind(a.min)
+                .bind(a.max)
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all points in a single transaction.
+    async fn write_batch(
+        &self,
+        meas: Measurements,
+        cutoff: Option<SystemTime>,
+    ) -> Result<(), DbError> {
+        self.ensure_ready().await?;
+        let mut tx = self.pool.begin().await?;
+
+        for (channel_id, points) in &meas {
+            let first_seen = points.iter().map(|p| p.time).min();
+            let last_seen = points.iter().map(|p| p.time).max();
+            if let (Some(first_seen), Some(last_seen)) = (first_seen, last_seen) {
+                sqlx::query::<DB>(&format!(
+                    "INSERT INTO Channels (id, first_seen, last_seen) VALUES ($1, $2, $3) \
+                    ON CONFLICT (id) DO UPDATE SET first_seen = {}, last_seen = {}",
+                    DB::least("Channels.first_seen", "excluded.first_seen"),
+                    DB::greatest("Channels.last_seen", "excluded.last_seen"),
+                ))
                 .bind(channel_id.to_string())
-                .bind(p.value)
-                .bind(DateTime::<Local>::from(p.time))
-                .execute(&mut self.client)
-                .await
-                {
-                    errors.push(err);
+                .bind(DateTime::<Utc>::from(first_seen))
+                .bind(DateTime::<Utc>::from(last_seen))
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        let mut raw = Vec::new();
+        let mut outdated = Measurements::default();
+        for (channel_id, points) in meas {
+            for p in points {
+                if cutoff.is_some_and(|cutoff| p.time < cutoff) {
+                    outdated.entry(channel_id.clone()).or_default().push(p);
+                } else {
+                    raw.push((channel_id.clone(), p));
                 }
             }
         }
-        errors
+
+        for chunk in raw.chunks(INSERT_CHUNK_LEN) {
+            let values = (0..chunk.len())
+                .map(|i| format!("(${}, ${}, ${})", 3 * i + 1, 3 * i + 2, 3 * i + 3))
+                .collect::<Vec<_>>()
+                .join(", ");
+            // Points of retried requests could be already inserted.
+            let sql = format!(
+                "INSERT INTO Measurements (channel_id, value, time) VALUES {values} \
+                ON CONFLICT (channel_id, time) DO NOTHING"
+            );
+            let mut q = sqlx::query::<DB>(&sql);
+            for (channel_id, p) in chunk {
+                q = q
+                    .bind(channel_id.to_string())
+                    .bind(p.value)
+                    .bind(DateTime::<Utc>::from(p.time));
+            }
+            q.execute(&mut *tx).await?;
+        }
+
+        if let Some(earliest) = raw.iter().map(|(_, p)| p.time).min() {
+            sqlx::query::<DB>(&format!(
+                "INSERT INTO RollupsDirty (id, dirty_from, version) VALUES (0, $1, 0) \
+                ON CONFLICT (id) DO UPDATE SET dirty_from = {}, version = RollupsDirty.version + 1",
+                DB::least("RollupsDirty.dirty_from", "excluded.dirty_from"),
+            ))
+            .bind(DateTime::<Utc>::from(earliest))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if !outdated.is_empty() {
+            Self::merge_into_rollups(&mut tx, &outdated).await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Check database health periodically in background.
+    ///
+    /// While database is unavailable it is checked with exponential backoff.
+    pub fn spawn_monitor(&self) {
+        let this = self.clone();
+        spawn(async move {
+            let mut backoff = Backoff::reconnect();
+            loop {
+                match this.check_health().await {
+                    Ok(()) => {
+                        this.set_health(Health::Available);
+                        backoff.reset();
+                        sleep(HEALTH_CHECK_PERIOD).await;
+                    }
+                    Err(err) => {
+                        this.set_health(Health::Unavailable {
+                            error: err.to_string(),
+                        });
+                        sleep(backoff.next_delay()).await;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Run [`Db::rollup`] periodically in background.
+    pub fn spawn_rollups(&self, period: Duration) {
+        let this = self.clone();
+        spawn(async move {
+            loop {
+                sleep(period).await;
+                if let Err(err) = this.rollup().await {
+                    log::error!("Cannot update rollups: {err}");
+                }
+            }
+        });
     }
 }
 
-pub struct DbStorage<C: Connection>
+/// Error of writing a batch of points, none of them is stored.
+#[derive(Debug)]
+pub struct BatchError {
+    /// Number of points in batch
+    pub points: usize,
+    pub source: DbError,
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot write batch of {} points: {}",
+            self.points, self.source
+        )
+    }
+}
+impl error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl<DB: Dialect> Recepient for Db<DB>
 where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+
+    usize: ColumnIndex<DB::Row>,
+    for<'q> String: Type<DB> + Encode<'q, DB>,
+    for<'q> i64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> f64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> DateTime<Utc>: Type<DB> + Encode<'q, DB>,
+    for<'q> Vec<u8>: Type<DB>,
 {
-    client: C,
+    type Error = BatchError;
+
+    async fn update(&mut self, meas: Measurements) -> Vec<Self::Error> {
+        let points = meas.values().map(Vec::len).sum();
+        let cutoff = self.retention_cutoff();
+        let result = self.write_batch(meas, cutoff).await;
+        match self.check_error(result) {
+            Ok(()) => Vec::new(),
+            Err(source) => vec![BatchError { points, source }],
+        }
+    }
 }
 
-impl<C: Connection> DbStorage<C>
+impl<DB: Dialect> History for Db<DB>
 where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
 
-    for<'q> Vec<u8>: Type<C::Database>,
+    usize: ColumnIndex<DB::Row>,
+    for<'q> String: Type<DB> + Encode<'q, DB>,
+    for<'q> i64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> f64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> DateTime<Utc>: Type<DB> + Encode<'q, DB>,
+    for<'q> Vec<u8>: Type<DB>,
 {
-    pub async fn new(mut client: C) -> Result<Self, sqlx::Error> {
-        sqlx::query(&format!(
-            "CREATE TABLE IF NOT EXISTS Storage (name VARCHAR PRIMARY KEY, value {})",
-            <Vec<u8> as Type<C::Database>>::type_info().name(),
-        ))
-        .execute(&mut client)
-        .await?;
-        Ok(Self { client })
+    type Error = DbError;
+
+    async fn points(&self, channel: ChannelId, query: PointsQuery) -> Result<Vec<Point>, DbError> {
+        let (conditions, bounds) = time_conditions(&query, 2);
+        let sql = format!(
+            "SELECT value, {} FROM Measurements WHERE channel_id = $1{} ORDER BY time LIMIT ${}",
+            DB::epoch_seconds("time"),
+            conditions,
+            bounds.len() + 2,
+        );
+
+        let result = async {
+            self.ensure_ready().await?;
+            let mut q = sqlx::query::<DB>(&sql).bind(channel.to_string());
+            for time in bounds {
+                q = q.bind(time);
+            }
+            let rows = q
+                .bind(i64::try_from(query.limit).unwrap_or(i64::MAX))
+                .fetch_all(&self.pool)
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    Ok(Point {
+                        value: row.try_get(0)?,
+                        time: from_epoch_seconds(row.try_get(1)?),
+                    })
+                })
+                .collect()
+        }
+        .await;
+        self.check_error(result)
+    }
+
+    async fn buckets(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> Result<Vec<Bucket>, DbError> {
+        let result = async {
+            self.ensure_ready().await?;
+
+            // Use the largest rollup that fits into bucket, it also contains points older than retention period.
+            let width_secs = width.as_secs_f64();
+            if let Some(rollup) = ROLLUPS.iter().rev().find(|r| {
+                let ratio = width_secs / r.width as f64;
+                ratio >= 1.0 && ratio.fract() == 0.0
+            }) {
+                return self.rollup_buckets(rollup, channel, query, width).await;
+            }
+
+            let (conditions, bounds) = time_conditions(&query, 3);
+            let sql = format!(
+                "SELECT {} AS bucket, COUNT(*), AVG(value), MIN(value), MAX(value) \
+                FROM Measurements WHERE channel_id = $1{} \
+                GROUP BY bucket ORDER BY bucket LIMIT ${}",
+                DB::bucket_index(&DB::epoch_seconds("time"), "$2"),
+                conditions,
+                bounds.len() + 3,
+            );
+
+            let mut q = sqlx::query::<DB>(&sql)
+                .bind(channel.to_string())
+                .bind(width.as_secs_f64());
+            for time in bounds {
+                q = q.bind(time);
+            }
+            let rows = q
+                .bind(i64::try_from(query.limit).unwrap_or(i64::MAX))
+                .fetch_all(&self.pool)
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    let index: i64 = row.try_get(0)?;
+                    let count: i64 = row.try_get(1)?;
+                    Ok(Bucket {
+                        time: index as f64 * width.as_secs_f64(),
+                        count: count as usize,
+                        mean: row.try_get(2)?,
+                        min: row.try_get(3)?,
+                        max: row.try_get(4)?,
+                    })
+                })
+                .collect()
+        }
+        .await;
+        self.check_error(result)
+    }
+}
+
+/// Conditions on `time` column for bounds of `query`.
+///
+/// Returns SQL fragment with parameters starting from `first_index` and values of these parameters.
+fn time_conditions(query: &PointsQuery, first_index: usize) -> (String, Vec<DateTime<Utc>>) {
+    let mut sql = String::new();
+    let mut bounds = Vec::new();
+    for (bound, op) in [(query.from, ">="), (query.to, "<=")] {
+        if let Some(time) = bound {
+            sql += &format!(" AND time {op} ${}", first_index + bounds.len());
+            bounds.push(DateTime::<Utc>::from(time));
+        }
+    }
+    (sql, bounds)
+}
+
+/// Storage in the `Storage` table of database.
+pub struct DbStorage<DB: Database> {
+    db: Db<DB>,
+}
+
+impl<DB: Database> DbStorage<DB> {
+    pub fn new(db: Db<DB>) -> Self {
+        Self { db }
     }
 }
 
-impl<C: Connection> Storage for DbStorage<C>
+impl<DB: Dialect> Storage for DbStorage<DB>
 where
-    for<'c> &'c mut C: Executor<'c, Database = C::Database>,
-    for<'q> <C::Database as Database>::Arguments<'q>: IntoArguments<'q, C::Database>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
 
-    usize: ColumnIndex<<C::Database as Database>::Row>,
-    for<'q> String: Type<C::Database> + Encode<'q, C::Database>,
-    for<'q> Vec<u8>: Type<C::Database> + Encode<'q, C::Database> + Decode<'q, C::Database>,
+    usize: ColumnIndex<DB::Row>,
+    for<'q> String: Type<DB> + Encode<'q, DB>,
+    for<'q> i64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> f64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> DateTime<Utc>: Type<DB> + Encode<'q, DB>,
+    for<'q> Vec<u8>: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
 {
-    type Error = sqlx::Error;
+    type Error = DbError;
     async fn load(&mut self, name: String) -> Result<Option<Vec<u8>>, Self::Error> {
-        let rows = sqlx::query::<C::Database>("SELECT (value) FROM Storage WHERE name = $1")
-            .bind(name)
-            .fetch_all(&mut self.client)
-            .await?;
-        assert!(rows.len() < 2);
-        match rows.first() {
-            Some(row) => Ok(Some(row.try_get(0)?)),
-            None => Ok(None),
+        let result = async {
+            self.db.ensure_ready().await?;
+            let rows = sqlx::query::<DB>("SELECT (value) FROM Storage WHERE name = $1")
+                .bind(name)
+                .fetch_all(&self.db.pool)
+                .await?;
+            assert!(rows.len() < 2);
+            match rows.first() {
+                Some(row) => Ok(Some(row.try_get(0)?)),
+                None => Ok(None),
+            }
         }
+        .await;
+        self.db.check_error(result)
     }
     async fn store(&mut self, name: String, value: Vec<u8>) -> Result<(), Self::Error> {
-        sqlx::query::<C::Database>(
-            "INSERT INTO Storage (name, value) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET value = $2",
-        )
-        .bind(name)
-        .bind(value)
-        .execute(&mut self.client)
-        .await?;
-        Ok(())
+        let result = async {
+            self.db.ensure_ready().await?;
+            sqlx::query::<DB>(
+                "INSERT INTO Storage (name, value) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET value = $2",
+            )
+            .bind(name)
+            .bind(value)
+            .execute(&self.db.pool)
+            .await?;
+            Ok(())
+        }
+        .await;
+        self.db.check_error(result)
     }
 }
@@ -0,0 +1,194 @@
+use futures::FutureExt;
+use rtherm_common::{error::AnyError, ChannelId, Point};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime, TryFromFloatSecsError},
+};
+
+/// Parameters of points query.
+#[derive(Clone, Default, Debug)]
+pub struct PointsQuery {
+    /// Earliest time of point (inclusive)
+    pub from: Option<SystemTime>,
+    /// Latest time of point (inclusive)
+    pub to: Option<SystemTime>,
+    /// Max number of points to return
+    pub limit: usize,
+}
+
+/// Query parameters as they come in URL.
+///
+/// Time is specified in seconds since Unix epoch.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PointsParams {
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub limit: Option<usize>,
+}
+
+impl PointsQuery {
+    pub const MAX_LIMIT: usize = 100000;
+}
+
+/// Parameters of buckets query as they come in URL.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BucketsParams {
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub limit: Option<usize>,
+    /// Bucket width in seconds
+    pub width: f64,
+}
+
+impl BucketsParams {
+    pub fn into_query(self) -> Result<(PointsQuery, Duration), TryFromFloatSecsError> {
+        let width = Duration::try_from_secs_f64(self.width)?;
+        let query = PointsQuery::try_from(PointsParams {
+            from: self.from,
+            to: self.to,
+            limit: self.limit,
+        })?;
+        Ok((query, width))
+    }
+}
+
+/// Stored point as it is returned by points query.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct TimedValue {
+    pub value: f64,
+    /// Time in seconds since Unix epoch, with fractional part
+    pub time: f64,
+}
+
+impl From<Point> for TimedValue {
+    fn from(point: Point) -> Self {
+        Self {
+            value: point.value,
+            time: point
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        }
+    }
+}
+
+/// Aggregated points within a time interval.
+#[derive(Clone, Debug, Serialize)]
+pub struct Bucket {
+    /// Start of interval in seconds since Unix epoch
+    pub time: f64,
+    pub count: usize,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl TryFrom<PointsParams> for PointsQuery {
+    type Error = TryFromFloatSecsError;
+    fn try_from(params: PointsParams) -> Result<Self, Self::Error> {
+        let time =
+            |secs: f64| Duration::try_from_secs_f64(secs).map(|d| SystemTime::UNIX_EPOCH + d);
+        Ok(Self {
+            from: params.from.map(time).transpose()?,
+            to: params.to.map(time).transpose()?,
+            limit: params.limit.unwrap_or(Self::MAX_LIMIT).min(Self::MAX_LIMIT),
+        })
+    }
+}
+
+/// Source of stored measurements.
+pub trait History: Send + Sync {
+    type Error: Error + Send;
+
+    /// Points of channel sorted by time.
+    fn points(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+    ) -> impl Future<Output = Result<Vec<Point>, Self::Error>> + Send + '_;
+
+    /// Points of channel aggregated into buckets of `width` sorted by time.
+    ///
+    /// Empty buckets are omitted.
+    fn buckets(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> impl Future<Output = Result<Vec<Bucket>, Self::Error>> + Send + '_;
+}
+
+type PointsResult = Result<Vec<Point>, AnyError>;
+type BucketsResult = Result<Vec<Bucket>, AnyError>;
+
+trait DynHistory: Send + Sync {
+    fn points_any(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+    ) -> Pin<Box<dyn Future<Output = PointsResult> + Send + '_>>;
+
+    fn buckets_any(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> Pin<Box<dyn Future<Output = BucketsResult> + Send + '_>>;
+}
+
+impl<H: History<Error: 'static>> DynHistory for H {
+    fn points_any(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+    ) -> Pin<Box<dyn Future<Output = PointsResult> + Send + '_>> {
+        Box::pin(
+            self.points(channel, query)
+                .map(|r| r.map_err(AnyError::new)),
+        )
+    }
+
+    fn buckets_any(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> Pin<Box<dyn Future<Output = BucketsResult> + Send + '_>> {
+        Box::pin(
+            self.buckets(channel, query, width)
+                .map(|r| r.map_err(AnyError::new)),
+        )
+    }
+}
+
+pub struct AnyHistory(Box<dyn DynHistory>);
+
+impl AnyHistory {
+    pub fn new<H: History<Error: 'static> + 'static>(history: H) -> Self {
+        Self(Box::new(history))
+    }
+}
+
+impl History for AnyHistory {
+    type Error = AnyError;
+    fn points(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+    ) -> impl Future<Output = Result<Vec<Point>, Self::Error>> + Send + '_ {
+        self.0.points_any(channel, query)
+    }
+
+    fn buckets(
+        &self,
+        channel: ChannelId,
+        query: PointsQuery,
+        width: Duration,
+    ) -> impl Future<Output = Result<Vec<Bucket>, Self::Error>> + Send + '_ {
+        self.0.buckets_any(channel, query, width)
+    }
+}
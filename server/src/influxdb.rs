@@ -0,0 +1,311 @@
+use reqwest::{header, Client, StatusCode, Url};
+use rtherm_common::{ChannelId, Measurements, Point};
+use std::{
+    error::Error,
+    fmt::{self, Display, Write},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    config::InfluxConfig,
+    recepient::Recepient,
+    retry::{with_retries, Transient},
+};
+
+/// Recepient that forwards measurements to InfluxDB-compatible endpoint using line protocol.
+///
+/// Points are written with `/api/v2/write`, which is also supported by InfluxDB 1.8+ and VictoriaMetrics.
+pub struct Influx {
+    client: Client,
+    url: Url,
+    token: Option<String>,
+    measurement: String,
+    batch_size: usize,
+    max_retries: u32,
+}
+
+#[derive(Debug)]
+pub enum InfluxError {
+    Http(reqwest::Error),
+    Status { status: StatusCode, body: String },
+}
+
+impl From<reqwest::Error> for InfluxError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+impl Display for InfluxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "InfluxDB request failed: {e}"),
+            Self::Status { status, body } => {
+                write!(f, "InfluxDB responded with {status}")?;
+                match body.trim() {
+                    "" => Ok(()),
+                    body => write!(f, ": {body}"),
+                }
+            }
+        }
+    }
+}
+
+impl Error for InfluxError {}
+
+impl Transient for InfluxError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(e) => e.is_transient(),
+            Self::Status { status, .. } => status.is_transient(),
+        }
+    }
+}
+
+/// Escape measurement name according to line protocol.
+fn escape_measurement(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, ',' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Influx {
+    pub fn new(config: InfluxConfig) -> Result<Self, String> {
+        let mut url = Url::parse(&config.url)
+            .and_then(|mut url| {
+                // Otherwise the last segment of path prefix is replaced on join.
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                url.join("api/v2/write")
+            })
+            .map_err(|e| format!("Invalid InfluxDB url {:?}: {e}", config.url))?;
+        url.query_pairs_mut()
+            .append_pair("bucket", &config.bucket)
+            .append_pair("precision", "ns");
+        if let Some(org) = &config.org {
+            url.query_pairs_mut().append_pair("org", org);
+        }
+        let client = Client::builder()
+            .timeout(Duration::from_secs_f64(config.timeout))
+            .build()
+            .map_err(|e| format!("Cannot create HTTP client: {e}"))?;
+        Ok(Self {
+            client,
+            url,
+            token: config.token,
+            measurement: escape_measurement(&config.measurement),
+            batch_size: config.batch_size.max(1),
+            max_retries: config.max_retries,
+        })
+    }
+
+    /// Encode point as a line of line protocol.
+    ///
+    /// Non-finite values cannot be represented and are skipped.
+    fn encode(&self, channel: &ChannelId, point: &Point, lines: &mut String) {
+        if !point.value.is_finite() {
+            return;
+        }
+        let time = match point.time.duration_since(UNIX_EPOCH) {
+            Ok(time) => time.as_nanos(),
+            Err(_) => return,
+        };
+        // Channel id contains only chars that need no escaping.
+        writeln!(
+            lines,
+            "{},channel={channel} value={:?} {time}",
+            self.measurement, point.value
+        )
+        .unwrap();
+    }
+
+    async fn send(&self, body: String) -> Result<(), InfluxError> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body);
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Token {token}"));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(InfluxError::Status {
+                status,
+                body: response.text().await.unwrap_or_default(),
+            })
+        }
+    }
+}
+
+impl Recepient for Influx {
+    type Error = InfluxError;
+
+    async fn update(&mut self, meas: Measurements) -> Vec<Self::Error> {
+        let points = meas
+            .iter()
+            .flat_map(|(channel, points)| points.iter().map(move |point| (channel, point)))
+            .collect::<Vec<_>>();
+        let mut errors = Vec::new();
+        for chunk in points.chunks(self.batch_size) {
+            let mut lines = String::new();
+            for (channel, point) in chunk {
+                self.encode(channel, point, &mut lines);
+            }
+            if lines.is_empty() {
+                continue;
+            }
+            if let Err(e) = with_retries(self.max_retries, || self.send(lines.clone())).await {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http, web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+
+    /// Write request received by mock server.
+    struct Request {
+        path: String,
+        query: String,
+        authorization: Option<String>,
+        body: String,
+    }
+
+    #[derive(Default)]
+    struct Mock {
+        /// Statuses of responses to the first requests, the rest succeed.
+        statuses: Mutex<Vec<u16>>,
+        requests: Mutex<Vec<Request>>,
+    }
+
+    async fn write(mock: web::Data<Mock>, request: HttpRequest, body: String) -> HttpResponse {
+        mock.requests.lock().unwrap().push(Request {
+            path: request.path().to_string(),
+            query: request.query_string().to_string(),
+            authorization: request
+                .headers()
+                .get(http::header::AUTHORIZATION)
+                .map(|value| value.to_str().unwrap().to_string()),
+            body,
+        });
+        let mut statuses = mock.statuses.lock().unwrap();
+        match statuses.is_empty() {
+            true => HttpResponse::NoContent().finish(),
+            false => HttpResponse::build(http::StatusCode::from_u16(statuses.remove(0)).unwrap())
+                .body("mock error"),
+        }
+    }
+
+    /// Start mock InfluxDB server, returns its url.
+    fn start(mock: web::Data<Mock>) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .route("/api/v2/write", web::post().to(write))
+                .route("/influx/api/v2/write", web::post().to(write))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    fn connect(url: String, batch_size: usize, max_retries: u32) -> Influx {
+        Influx::new(InfluxConfig {
+            url,
+            bucket: "home".to_string(),
+            org: None,
+            token: Some("secret".to_string()),
+            measurement: "temp room".to_string(),
+            batch_size,
+            max_retries,
+            timeout: 5.0,
+        })
+        .unwrap()
+    }
+
+    fn measurements(values: &[f64]) -> Measurements {
+        let points = values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| Point {
+                value,
+                time: UNIX_EPOCH + Duration::from_secs(i as u64 + 1),
+            })
+            .collect();
+        Measurements::from_iter([(ChannelId::try_from("a").unwrap(), points)])
+    }
+
+    #[actix_web::test]
+    async fn writes_batches_of_line_protocol() {
+        let mock = web::Data::new(Mock::default());
+        let mut influx = connect(start(mock.clone()), 2, 0);
+
+        let errors = influx.update(measurements(&[1.0, f64::NAN, 2.5])).await;
+        assert!(errors.is_empty(), "{errors:?}");
+
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].query, "bucket=home&precision=ns");
+        assert_eq!(requests[0].authorization.as_deref(), Some("Token secret"));
+        assert_eq!(
+            requests[0].body,
+            "temp\\ room,channel=a value=1.0 1000000000\n"
+        );
+        assert_eq!(
+            requests[1].body,
+            "temp\\ room,channel=a value=2.5 3000000000\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn url_can_have_path_prefix() {
+        let mock = web::Data::new(Mock::default());
+        let url = start(mock.clone());
+        for prefixed in [format!("{url}/influx"), format!("{url}/influx/")] {
+            let mut influx = connect(prefixed, 10, 0);
+            let errors = influx.update(measurements(&[1.0])).await;
+            assert!(errors.is_empty(), "{errors:?}");
+        }
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.path == "/influx/api/v2/write"));
+    }
+
+    #[actix_web::test]
+    async fn retries_transient_errors_only() {
+        let mock = web::Data::new(Mock::default());
+        *mock.statuses.lock().unwrap() = vec![503, 429];
+        let mut influx = connect(start(mock.clone()), 10, 2);
+        let errors = influx.update(measurements(&[1.0])).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(mock.requests.lock().unwrap().len(), 3);
+
+        let mock = web::Data::new(Mock::default());
+        *mock.statuses.lock().unwrap() = vec![400];
+        let mut influx = connect(start(mock.clone()), 10, 2);
+        let errors = influx.update(measurements(&[1.0])).await;
+        assert!(
+            matches!(errors[..], [InfluxError::Status { status, .. }] if status == 400),
+            "{errors:?}"
+        );
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+    }
+}
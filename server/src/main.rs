@@ -1,59 +1,86 @@
+mod alerting;
+mod auth;
+mod batches;
 mod config;
 mod db;
+mod history;
+#[cfg(feature = "influxdb")]
+mod influxdb;
+mod metrics;
+mod migrations;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod queue;
 mod recepient;
+mod retry;
 mod statistics;
 mod storage;
 #[cfg(feature = "telegram")]
 mod telegram;
+mod wal;
+#[cfg(feature = "webhook")]
+mod webhook;
 
 use self::{
-    config::{Config, HttpConfig},
-    db::Db,
-    recepient::{AnyRecepient, Recepient},
+    alerting::{Alerting, AnyNotifier},
+    auth::{Auth, AuthError, Credentials},
+    batches::{BatchKey, RecentBatches},
+    config::{Config, HttpConfig, PoolConfig},
+    db::{Db, Health},
+    history::{AnyHistory, BucketsParams, History, PointsParams, PointsQuery, TimedValue},
+    metrics::{MetricsWriter, ProvideCounters},
+    queue::{Pipeline, QueueError, RecepientQueue},
+    recepient::AnyRecepient,
     statistics::Statistics,
+    wal::Wal,
 };
 use actix_files as fs;
-use actix_web::{web, App, HttpServer, Responder, Result};
+use actix_web::{
+    error,
+    http::{header, StatusCode},
+    web, App, HttpRequest, HttpResponse, HttpServer, Responder, Result,
+};
 use config::StorageType;
 use db::DbStorage;
-use rtherm_common::{ChannelId, ProvideRequest};
-use sqlx::Connection;
+use rtherm_common::{signature, ChannelId, Encoding, ProvideRequest};
+use sqlx::pool::PoolOptions;
 use statistics::ChannelStatistics;
-use std::{collections::HashMap, env, io};
+use std::{
+    collections::{HashMap, HashSet},
+    env, io,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 use storage::{AnyStorage, FileStorage, MemStorage};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
+
+fn pool_options<DB: sqlx::Database>(config: &PoolConfig) -> PoolOptions<DB> {
+    PoolOptions::new()
+        .max_connections(config.max_connections)
+        .acquire_timeout(Duration::from_secs_f64(config.acquire_timeout))
+}
 
 #[cfg(feature = "postgres")]
-async fn postgres_connection(
+fn postgres_pool(
     config: &self::config::PostgresConfig,
-) -> sqlx::postgres::PgConnection {
-    loop {
-        let res = sqlx::postgres::PgConnection::connect(&format!(
+    pool_config: &PoolConfig,
+) -> sqlx::postgres::PgPool {
+    pool_options(pool_config)
+        .connect_lazy(&format!(
             "postgres://{}:{}@{}/rtherm",
             config.user, config.password, config.host
         ))
-        .await;
-        match res {
-            Ok(conn) => break conn,
-            Err(e) => {
-                if let sqlx::Error::Database(e) = &e {
-                    if e.code().as_deref() == Some("57P03") {
-                        log::info!("Waiting for database to start ...");
-                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                        continue;
-                    }
-                }
-                panic!("{e}")
-            }
-        }
-    }
+        .unwrap_or_else(|e| panic!("Invalid Postgres config: {e}"))
 }
 
 #[cfg(feature = "sqlite")]
-async fn sqlite_connection(config: &self::config::SqliteConfig) -> sqlx::sqlite::SqliteConnection {
-    sqlx::sqlite::SqliteConnection::connect(&config.path)
-        .await
-        .unwrap()
+fn sqlite_pool(
+    config: &self::config::SqliteConfig,
+    pool_config: &PoolConfig,
+) -> sqlx::sqlite::SqlitePool {
+    pool_options(pool_config)
+        .connect_lazy(&config.path)
+        .unwrap_or_else(|e| panic!("Invalid SQLite config: {e}"))
 }
 
 #[tokio::main]
@@ -68,20 +95,77 @@ async fn main() {
     };
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
-    let mut recepients = Vec::<AnyRecepient>::new();
+    let mut pipeline = Pipeline::default();
+    let queue_capacity = config.queue.capacity;
+    let wal = match &config.wal {
+        Some(wal_config) => Some(Arc::new(Wal::open(wal_config).await.unwrap_or_else(|e| {
+            panic!("Error opening write-ahead log {:?}: {e}", wal_config.path)
+        }))),
+        None => None,
+    };
+    let mut history = None;
+    let mut health = HashMap::new();
+    let retention = config
+        .db
+        .as_ref()
+        .map(|db| db.retention.clone())
+        .unwrap_or_default();
+    let rollup_period = Duration::from_secs_f64(retention.rollup_period);
+    let pool_config = config
+        .db
+        .as_ref()
+        .map(|db| db.pool.clone())
+        .unwrap_or_default();
+    let startup_timeout = Duration::from_secs_f64(pool_config.startup_timeout);
 
     #[cfg(feature = "postgres")]
-    if let Some(db_config) = config.db.as_ref().and_then(|db| db.postgres.as_ref()) {
-        let conn = postgres_connection(db_config).await;
-        recepients.push(AnyRecepient::new(Db::new(conn).await.unwrap()));
-        log::info!("Postgres database connected");
+    let postgres = config
+        .db
+        .as_ref()
+        .and_then(|db| db.postgres.as_ref())
+        .map(|db_config| Db::new(postgres_pool(db_config, &pool_config), &retention));
+    #[cfg(feature = "postgres")]
+    if let Some(db) = &postgres {
+        db.spawn_monitor();
+        if db.wait_available(startup_timeout).await {
+            log::info!("Postgres database connected");
+        } else {
+            log::error!("Postgres database is not available, continue without it");
+        }
+        db.spawn_rollups(rollup_period);
+        pipeline.push(RecepientQueue::spawn(
+            "postgres",
+            AnyRecepient::new(db.clone()),
+            queue_capacity,
+            wal.clone(),
+        ));
+        history.get_or_insert(AnyHistory::new(db.clone()));
+        health.insert("postgres", db.health());
     }
 
     #[cfg(feature = "sqlite")]
-    if let Some(db_config) = config.db.as_ref().and_then(|db| db.sqlite.as_ref()) {
-        let conn = sqlite_connection(db_config).await;
-        recepients.push(AnyRecepient::new(Db::new(conn).await.unwrap()));
-        log::info!("SQLite database connected");
+    let sqlite = config
+        .db
+        .as_ref()
+        .and_then(|db| db.sqlite.as_ref())
+        .map(|db_config| Db::new(sqlite_pool(db_config, &pool_config), &retention));
+    #[cfg(feature = "sqlite")]
+    if let Some(db) = &sqlite {
+        db.spawn_monitor();
+        if db.wait_available(startup_timeout).await {
+            log::info!("SQLite database connected");
+        } else {
+            log::error!("SQLite database is not available, continue without it");
+        }
+        db.spawn_rollups(rollup_period);
+        pipeline.push(RecepientQueue::spawn(
+            "sqlite",
+            AnyRecepient::new(db.clone()),
+            queue_capacity,
+            wal.clone(),
+        ));
+        history.get_or_insert(AnyHistory::new(db.clone()));
+        health.insert("sqlite", db.health());
     }
 
     let storage: AnyStorage = match config.storage.type_ {
@@ -100,43 +184,119 @@ async fn main() {
             let mut db_storage = None;
 
             #[cfg(feature = "postgres")]
-            if let Some(db_config) = config.db.as_ref().and_then(|db| db.postgres.as_ref()) {
-                db_storage = Some(AnyStorage::new(
-                    DbStorage::new(postgres_connection(db_config).await)
-                        .await
-                        .unwrap(),
-                ));
+            if let Some(db) = &postgres {
+                db_storage = Some(AnyStorage::new(DbStorage::new(db.clone())));
             }
             #[cfg(feature = "sqlite")]
-            if let Some(db_config) = config.db.as_ref().and_then(|db| db.sqlite.as_ref()) {
-                db_storage = Some(AnyStorage::new(
-                    DbStorage::new(sqlite_connection(db_config).await)
-                        .await
-                        .unwrap(),
-                ));
+            if let Some(db) = &sqlite {
+                db_storage = Some(AnyStorage::new(DbStorage::new(db.clone())));
             }
 
             db_storage.expect(r#"Storage type is set to "db" but no databases found"#)
         }
     };
 
+    let mut notifiers = Vec::<(String, AnyNotifier)>::new();
+
     #[cfg(feature = "telegram")]
     if let Some(tg_config) = config.telegram {
-        recepients.push(AnyRecepient::new(
-            telegram::Telegram::new(tg_config, storage).await,
+        let telegram = telegram::Telegram::new(tg_config, storage.clone()).await;
+        pipeline.push(RecepientQueue::spawn(
+            "telegram",
+            AnyRecepient::new(telegram.clone()),
+            queue_capacity,
+            // Only recent values for digests are kept.
+            None,
         ));
+        notifiers.push(("telegram".into(), AnyNotifier::new(telegram)));
         log::info!("Telegram bot started");
     }
 
-    serve(config.http, recepients).await.unwrap();
+    #[cfg(feature = "influxdb")]
+    if let Some(influx_config) = config.influxdb {
+        pipeline.push(RecepientQueue::spawn(
+            "influxdb",
+            AnyRecepient::new(influxdb::Influx::new(influx_config).unwrap()),
+            queue_capacity,
+            wal.clone(),
+        ));
+        log::info!("InfluxDB export enabled");
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt_config) = config.mqtt {
+        pipeline.push(RecepientQueue::spawn(
+            "mqtt",
+            AnyRecepient::new(mqtt::Mqtt::new(mqtt_config).unwrap()),
+            queue_capacity,
+            // Replayed values would be published again.
+            None,
+        ));
+        log::info!("MQTT publishing enabled");
+    }
+
+    #[cfg(feature = "webhook")]
+    if let Some(webhook_config) = config.webhook {
+        notifiers.push((
+            "webhook".into(),
+            AnyNotifier::new(webhook::Webhook::new(webhook_config).unwrap()),
+        ));
+        log::info!("Webhook alerts enabled");
+    }
+
+    if !notifiers.is_empty() {
+        let alerting = Alerting::new(config.alerting, notifiers, storage).await;
+        pipeline.push(RecepientQueue::spawn(
+            "alerting",
+            AnyRecepient::new(alerting),
+            queue_capacity,
+            // Replayed values would trigger notifications again.
+            None,
+        ));
+    }
+
+    let mut batches = RecentBatches::default();
+    if let Some(wal) = &wal {
+        wal.retain_checkpoints(pipeline.logged_names()).await;
+        // Requests in log could be retried by clients that did not get response.
+        match wal.batch_ids().await {
+            Ok(ids) => ids.into_iter().for_each(|id| batches.insert(id)),
+            Err(e) => log::error!("Cannot read write-ahead log: {e}"),
+        }
+    }
+
+    serve(
+        config.http,
+        config.auth.map(Auth::new),
+        history,
+        health,
+        State {
+            info: Statistics::default(),
+            pipeline,
+            wal,
+            batches,
+            pending: HashSet::new(),
+        },
+    )
+    .await
+    .unwrap();
 }
 
-struct State<R: Recepient> {
+/// Max size of decompressed request body
+const MAX_PAYLOAD_SIZE: usize = 2 * 1024 * 1024;
+/// Delay suggested to client when recepient queue is full
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+struct State {
     info: Statistics,
-    recepient: R,
+    pipeline: Pipeline,
+    wal: Option<Arc<Wal>>,
+    batches: RecentBatches,
+    /// Batches that are being written to log
+    pending: HashSet<BatchKey>,
 }
 
-impl<R: Recepient> State<R> {
+impl State {
     fn summary(&self) -> HashMap<ChannelId, ChannelStatistics> {
         self.info
             .channels
@@ -146,19 +306,33 @@ impl<R: Recepient> State<R> {
     }
 }
 
-pub async fn serve<R: Recepient + Send + 'static>(
+async fn serve(
     config: HttpConfig,
-    recepient: R,
+    auth: Option<Auth>,
+    history: Option<AnyHistory>,
+    health: HashMap<&'static str, watch::Receiver<Health>>,
+    state: State,
 ) -> io::Result<()> {
-    let state = web::Data::new(Mutex::new(State {
-        info: Statistics::default(),
-        recepient,
-    }));
+    let auth = web::Data::new(auth);
+    let history = web::Data::new(history);
+    let health = web::Data::new(health);
+    let counters = web::Data::new(ProvideCounters::default());
+    let state = web::Data::new(Mutex::new(state));
     let server = HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
-            .route("/summary", web::get().to(summary::<R>))
-            .route("/provide", web::post().to(provide::<R>))
+            .app_data(auth.clone())
+            .app_data(history.clone())
+            .app_data(health.clone())
+            .app_data(counters.clone())
+            .app_data(web::PayloadConfig::new(MAX_PAYLOAD_SIZE))
+            .route("/summary", web::get().to(summary))
+            .route("/health", web::get().to(health_status))
+            .route("/recepients", web::get().to(recepients))
+            .route("/metrics", web::get().to(metrics))
+            .route("/provide", web::post().to(provide))
+            .route("/channels/{id}/points", web::get().to(points))
+            .route("/channels/{id}/buckets", web::get().to(buckets))
             .service(fs::Files::new("/", "./static").index_file("index.html"))
     })
     .bind((config.host, config.port))?;
@@ -166,21 +340,388 @@ pub async fn serve<R: Recepient + Send + 'static>(
     server.run().await
 }
 
-async fn provide<R: Recepient>(
-    data: web::Data<Mutex<State<R>>>,
-    request: web::Json<ProvideRequest>,
+async fn provide(
+    data: web::Data<Mutex<State>>,
+    auth: web::Data<Option<Auth>>,
+    counters: web::Data<ProvideCounters>,
+    http_request: HttpRequest,
+    body: web::Bytes,
 ) -> Result<&'static str> {
-    let request = request.into_inner();
-    let mut guard = data.lock().await;
-    let State { info, recepient } = &mut *guard;
+    let result = accept(&data, &auth, &counters, &http_request, &body).await;
+    counters.response(match &result {
+        Ok(()) => StatusCode::OK.as_u16(),
+        Err(e) => e.as_response_error().status_code().as_u16(),
+    });
+    result.map(|()| "Accepted")
+}
+
+async fn accept(
+    data: &Mutex<State>,
+    auth: &Option<Auth>,
+    counters: &ProvideCounters,
+    http_request: &HttpRequest,
+    body: &[u8],
+) -> Result<()> {
+    let encoding = match http_request.headers().get(header::CONTENT_TYPE) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(Encoding::from_content_type)
+            .ok_or_else(|| error::ErrorUnsupportedMediaType("Unsupported content type"))?,
+        None => Encoding::default(),
+    };
+    let auth_error = |e: AuthError| match e {
+        AuthError::ForbiddenChannel(..) => error::ErrorForbidden(e),
+        _ => error::ErrorUnauthorized(e),
+    };
+    // Body of unauthenticated client is not even decoded.
+    let client = match auth {
+        Some(auth) => Some(
+            auth.authenticate(credentials(http_request, body)?)
+                .map_err(auth_error)?,
+        ),
+        None => None,
+    };
+    let request = ProvideRequest::decode(encoding, body).map_err(error::ErrorBadRequest)?;
+    if let (Some(auth), Some(client)) = (auth, &client) {
+        auth.authorize(client, &request.measurements)
+            .map_err(auth_error)?;
+    }
+    let client = client.map(|client| client.id);
     log::debug!("Measurements obtained: {:?}", request);
-    info.update(request.measurements.clone());
-    for err in recepient.update(request.measurements).await {
-        log::error!("Recepient update error: {err}");
+
+    let batch = request.batch_id.map(|id| (client.clone(), id));
+    let (reservation, wal) = {
+        let mut state = data.lock().await;
+        if let Some(batch) = &batch {
+            if state.batches.contains(batch) {
+                log::info!(
+                    "Batch {:016x} has already been applied, skipping it",
+                    batch.1
+                );
+                counters.duplicate();
+                return Ok(());
+            }
+            if state.pending.contains(batch) {
+                let message = format!("Batch {:016x} is being applied", batch.1);
+                let response = HttpResponse::Conflict()
+                    .insert_header((header::RETRY_AFTER, RETRY_AFTER.as_secs().to_string()))
+                    .body(message.clone());
+                return Err(error::InternalError::from_response(message, response).into());
+            }
+        }
+        let reservation = state.pipeline.reserve().map_err(|e| match e {
+            QueueError::Full(..) => {
+                let response = HttpResponse::ServiceUnavailable()
+                    .insert_header((header::RETRY_AFTER, RETRY_AFTER.as_secs().to_string()))
+                    .body(e.to_string());
+                error::InternalError::from_response(e, response).into()
+            }
+            QueueError::Closed(..) => {
+                log::error!("{e}");
+                error::ErrorInternalServerError(e)
+            }
+        })?;
+        if let Some(batch) = &batch {
+            state.pending.insert(batch.clone());
+        }
+        (reservation, state.wal.clone())
+    };
+
+    // Log is synced without holding the state, so that other clients are not blocked.
+    let send = |seq| reservation.send(seq, request.measurements.clone());
+    let result = match &wal {
+        Some(wal) => wal
+            .append(client.as_deref(), &request, |seq| send(Some(seq)))
+            .await
+            .map(drop),
+        None => {
+            send(None);
+            Ok(())
+        }
+    };
+
+    let mut state = data.lock().await;
+    if let Some(batch) = &batch {
+        state.pending.remove(batch);
     }
-    Ok("Accepted")
+    result.map_err(error::ErrorInternalServerError)?;
+    counters.accepted(request.measurements.values().map(Vec::len).sum());
+    if let Some(batch) = batch {
+        state.batches.insert(batch);
+    }
+    state.info.update(request.measurements);
+    Ok(())
+}
+
+fn credentials<'a>(request: &'a HttpRequest, body: &'a [u8]) -> Result<Option<Credentials<'a>>> {
+    let headers = request.headers();
+    let get = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(signature) = get(signature::SIGNATURE_HEADER) {
+        let missing = |name| error::ErrorUnauthorized(format!("{name} header is missing"));
+        return Ok(Some(Credentials::Signed {
+            key_id: get(signature::KEY_ID_HEADER)
+                .ok_or_else(|| missing(signature::KEY_ID_HEADER))?,
+            timestamp: get(signature::TIMESTAMP_HEADER)
+                .ok_or_else(|| missing(signature::TIMESTAMP_HEADER))?
+                .parse()
+                .map_err(error::ErrorUnauthorized)?,
+            nonce: get(signature::NONCE_HEADER).ok_or_else(|| missing(signature::NONCE_HEADER))?,
+            signature,
+            body,
+        }));
+    }
+    Ok(get(header::AUTHORIZATION.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(Credentials::Token))
+}
+
+async fn health_status(
+    health: web::Data<HashMap<&'static str, watch::Receiver<Health>>>,
+) -> HttpResponse {
+    let health: HashMap<_, _> = health
+        .iter()
+        .map(|(name, health)| (*name, health.borrow().clone()))
+        .collect();
+    let status = if health.values().all(Health::is_available) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponse::build(status).json(health)
 }
 
-async fn summary<R: Recepient>(data: web::Data<Mutex<State<R>>>) -> Result<impl Responder> {
+async fn summary(data: web::Data<Mutex<State>>) -> Result<impl Responder> {
     Ok(web::Json(data.lock().await.summary()))
 }
+
+async fn recepients(data: web::Data<Mutex<State>>) -> Result<impl Responder> {
+    Ok(web::Json(data.lock().await.pipeline.status()))
+}
+
+async fn metrics(
+    data: web::Data<Mutex<State>>,
+    health: web::Data<HashMap<&'static str, watch::Receiver<Health>>>,
+    counters: web::Data<ProvideCounters>,
+) -> HttpResponse {
+    let mut metrics = MetricsWriter::default();
+    {
+        let state = data.lock().await;
+        let mut channels: Vec<_> = state.info.channels.iter().collect();
+        channels.sort_by_key(|(id, _)| *id);
+
+        metrics.family("rtherm_channel_value", "gauge", "Last value of channel");
+        for (id, values) in &channels {
+            if let Some(point) = values.statistics().last {
+                metrics.sample("rtherm_channel_value", &[("channel", id)], point.value);
+            }
+        }
+        metrics.family(
+            "rtherm_channel_last_seen_seconds",
+            "gauge",
+            "Time of the last point of channel in seconds since Unix epoch",
+        );
+        for (id, values) in &channels {
+            if let Some(point) = values.statistics().last {
+                let time = point.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                metrics.sample(
+                    "rtherm_channel_last_seen_seconds",
+                    &[("channel", id)],
+                    time.as_secs_f64(),
+                );
+            }
+        }
+        metrics.family(
+            "rtherm_channel_history_points",
+            "gauge",
+            "Number of points of channel kept in memory",
+        );
+        for (id, values) in &channels {
+            metrics.sample(
+                "rtherm_channel_history_points",
+                &[("channel", id)],
+                values.len() as f64,
+            );
+        }
+
+        let queues = state.pipeline.status();
+        metrics.family(
+            "rtherm_recepient_updates_total",
+            "counter",
+            "Number of updates processed by recepient",
+        );
+        for queue in &queues {
+            metrics.sample(
+                "rtherm_recepient_updates_total",
+                &[("recepient", &queue.name)],
+                queue.stats.processed as f64,
+            );
+        }
+        metrics.family(
+            "rtherm_recepient_errors_total",
+            "counter",
+            "Number of errors reported by recepient",
+        );
+        for queue in &queues {
+            metrics.sample(
+                "rtherm_recepient_errors_total",
+                &[("recepient", &queue.name)],
+                queue.stats.errors as f64,
+            );
+        }
+        metrics.family(
+            "rtherm_recepient_queue_depth",
+            "gauge",
+            "Number of updates waiting in queue of recepient",
+        );
+        for queue in &queues {
+            metrics.sample(
+                "rtherm_recepient_queue_depth",
+                &[("recepient", &queue.name)],
+                queue.depth as f64,
+            );
+        }
+    }
+    counters.write(&mut metrics);
+    metrics.family(
+        "rtherm_database_up",
+        "gauge",
+        "Whether database is available",
+    );
+    let mut health: Vec<_> = health.iter().collect();
+    health.sort_by_key(|(name, _)| **name);
+    for (name, health) in health {
+        metrics.sample(
+            "rtherm_database_up",
+            &[("database", name)],
+            if health.borrow().is_available() {
+                1.0
+            } else {
+                0.0
+            },
+        );
+    }
+    HttpResponse::Ok()
+        .content_type(MetricsWriter::CONTENT_TYPE)
+        .body(metrics.finish())
+}
+
+async fn points(
+    history: web::Data<Option<AnyHistory>>,
+    id: web::Path<String>,
+    params: web::Query<PointsParams>,
+) -> Result<impl Responder> {
+    let history = history
+        .as_ref()
+        .as_ref()
+        .ok_or_else(|| error::ErrorNotFound("History is not available without database"))?;
+    let id = ChannelId::try_from(id.into_inner()).map_err(error::ErrorBadRequest)?;
+    let query = PointsQuery::try_from(params.into_inner()).map_err(error::ErrorBadRequest)?;
+    let points = history
+        .points(id, query)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(web::Json(
+        points.into_iter().map(TimedValue::from).collect::<Vec<_>>(),
+    ))
+}
+
+async fn buckets(
+    history: web::Data<Option<AnyHistory>>,
+    id: web::Path<String>,
+    params: web::Query<BucketsParams>,
+) -> Result<impl Responder> {
+    let history = history
+        .as_ref()
+        .as_ref()
+        .ok_or_else(|| error::ErrorNotFound("History is not available without database"))?;
+    let id = ChannelId::try_from(id.into_inner()).map_err(error::ErrorBadRequest)?;
+    let (query, width) = params
+        .into_inner()
+        .into_query()
+        .map_err(error::ErrorBadRequest)?;
+    if width.is_zero() {
+        return Err(error::ErrorBadRequest("Bucket width must be positive"));
+    }
+    let buckets = history
+        .buckets(id, query, width)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(web::Json(buckets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, KeyConfig};
+    use actix_web::test;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{io::Write, time::SystemTime};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn signature_covers_decompressed_body() {
+        let state = web::Data::new(Mutex::new(State {
+            info: Statistics::default(),
+            pipeline: Pipeline::default(),
+            wal: None,
+            batches: RecentBatches::default(),
+            pending: HashSet::new(),
+        }));
+        let auth = Auth::new(AuthConfig {
+            tokens: Vec::new(),
+            keys: vec![KeyConfig {
+                id: "pi".to_string(),
+                key: "key".to_string(),
+                channel_prefix: String::new(),
+            }],
+            replay_window: 300.0,
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(web::Data::new(Some(auth)))
+                .app_data(web::Data::new(ProvideCounters::default()))
+                .route("/provide", web::post().to(provide)),
+        )
+        .await;
+
+        let body = br#"{"version":2,"measurements":{"a":[{"value":1.0,"time_ns":1}]}}"#;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let request = |nonce: &str, signed: &[u8]| {
+            test::TestRequest::post()
+                .uri("/provide")
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .insert_header((header::CONTENT_ENCODING, "gzip"))
+                .insert_header((signature::KEY_ID_HEADER, "pi"))
+                .insert_header((signature::TIMESTAMP_HEADER, timestamp.to_string()))
+                .insert_header((signature::NONCE_HEADER, nonce))
+                .insert_header((
+                    signature::SIGNATURE_HEADER,
+                    signature::sign(b"key", timestamp, nonce, signed),
+                ))
+                .set_payload(gzip(body))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, request("n1", &gzip(body))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.lock().await.summary().is_empty());
+
+        let response = test::call_service(&app, request("n2", body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state
+            .lock()
+            .await
+            .summary()
+            .contains_key(&ChannelId::try_from("a").unwrap()));
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Counters of `/provide` requests.
+#[derive(Default, Debug)]
+pub struct ProvideCounters {
+    /// Number of responses by HTTP status code
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Number of batches skipped because they have already been applied
+    duplicates: AtomicU64,
+    /// Number of accepted points
+    points: AtomicU64,
+}
+
+impl ProvideCounters {
+    pub fn response(&self, status: u16) {
+        *self.responses.lock().unwrap().entry(status).or_default() += 1;
+    }
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn accepted(&self, points: usize) {
+        self.points.fetch_add(points as u64, Ordering::Relaxed);
+    }
+
+    pub fn write(&self, metrics: &mut MetricsWriter) {
+        metrics.family(
+            "rtherm_provide_requests_total",
+            "counter",
+            "Number of provide requests by response status",
+        );
+        for (status, count) in self.responses.lock().unwrap().iter() {
+            metrics.sample(
+                "rtherm_provide_requests_total",
+                &[("status", &status.to_string())],
+                *count as f64,
+            );
+        }
+        metrics.family(
+            "rtherm_provide_duplicate_batches_total",
+            "counter",
+            "Number of batches skipped because they have already been applied",
+        );
+        metrics.sample(
+            "rtherm_provide_duplicate_batches_total",
+            &[],
+            self.duplicates.load(Ordering::Relaxed) as f64,
+        );
+        metrics.family(
+            "rtherm_provide_points_total",
+            "counter",
+            "Number of accepted points",
+        );
+        metrics.sample(
+            "rtherm_provide_points_total",
+            &[],
+            self.points.load(Ordering::Relaxed) as f64,
+        );
+    }
+}
+
+/// Writer of metrics in Prometheus text exposition format.
+#[derive(Default, Debug)]
+pub struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    pub const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4; charset=utf-8";
+
+    /// Start metric family, must be followed by its samples.
+    pub fn family(&mut self, name: &str, type_: &str, help: &str) {
+        writeln!(self.text, "# HELP {name} {help}").unwrap();
+        writeln!(self.text, "# TYPE {name} {type_}").unwrap();
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.text.push(',');
+                }
+                write!(self.text, "{label}=\"{}\"", escape_label(value)).unwrap();
+            }
+            self.text.push('}');
+        }
+        let value = match value {
+            v if v.is_nan() => "NaN".to_string(),
+            v if v == f64::INFINITY => "+Inf".to_string(),
+            v if v == f64::NEG_INFINITY => "-Inf".to_string(),
+            v => v.to_string(),
+        };
+        writeln!(self.text, " {value}").unwrap();
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
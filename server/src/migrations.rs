@@ -0,0 +1,162 @@
+use sqlx::{ColumnIndex, Connection, Decode, Encode, Executor, IntoArguments, Row, Type, TypeInfo};
+
+use crate::db::{DbError, Dialect};
+
+/// Schema change that is applied to database once.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    /// SQL statements, `{blob}` is replaced with database-specific binary type
+    /// and `{utc:<column>}` with [`Dialect::utc_timestamp`] of column.
+    statements: &'static [&'static str],
+}
+
+/// Migrations in order of their versions.
+///
+/// Applied migrations must never be changed, add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        // Tables could be created before migrations were introduced.
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS Measurements (channel_id VARCHAR, value FLOAT, time TIMESTAMP)",
+            "CREATE TABLE IF NOT EXISTS MeasurementsHourly (\
+            channel_id VARCHAR, time BIGINT, count BIGINT, mean FLOAT, min FLOAT, max FLOAT, \
+            PRIMARY KEY (channel_id, time))",
+            "CREATE TABLE IF NOT EXISTS MeasurementsDaily (\
+            channel_id VARCHAR, time BIGINT, count BIGINT, mean FLOAT, min FLOAT, max FLOAT, \
+            PRIMARY KEY (channel_id, time))",
+            "CREATE TABLE IF NOT EXISTS Storage (name VARCHAR PRIMARY KEY, value {blob})",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Index measurements by channel and time",
+        statements: &[
+            "CREATE INDEX IF NOT EXISTS MeasurementsChannelTime ON Measurements (channel_id, time)",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Channels metadata",
+        statements: &[
+            "CREATE TABLE Channels (\
+            id VARCHAR PRIMARY KEY, first_seen TIMESTAMP NOT NULL, last_seen TIMESTAMP NOT NULL)",
+            "INSERT INTO Channels (id, first_seen, last_seen) \
+            SELECT channel_id, MIN(time), MAX(time) FROM Measurements GROUP BY channel_id",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Unique measurement per channel and time",
+        // Points could be inserted more than once by retried requests.
+        statements: &[
+            "CREATE TABLE MeasurementsUnique (channel_id VARCHAR, value FLOAT, time TIMESTAMP)",
+            "INSERT INTO MeasurementsUnique (channel_id, value, time) \
+            SELECT channel_id, AVG(value), time FROM Measurements GROUP BY channel_id, time",
+            "DROP TABLE Measurements",
+            "ALTER TABLE MeasurementsUnique RENAME TO Measurements",
+            "CREATE UNIQUE INDEX MeasurementsChannelTime ON Measurements (channel_id, time)",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Persistent rollup watermark",
+        // Earlier watermark was kept in memory only, so recompute rollups of all raw points.
+        statements: &[
+            "CREATE TABLE RollupsDirty (\
+            id INTEGER PRIMARY KEY, dirty_from TIMESTAMP NOT NULL, version BIGINT NOT NULL)",
+            "INSERT INTO RollupsDirty (id, dirty_from, version) \
+            SELECT 0, MIN(time), 0 FROM Measurements HAVING COUNT(*) > 0",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Timestamps in UTC",
+        // Timestamps were written with local offset, which changes with DST or time zone,
+        // so the same instant could be stored twice.
+        statements: &[
+            "CREATE TABLE MeasurementsUtc (channel_id VARCHAR, value FLOAT, time TIMESTAMP)",
+            "INSERT INTO MeasurementsUtc (channel_id, value, time) \
+            SELECT channel_id, AVG(value), {utc:time} AS utc_time FROM Measurements \
+            GROUP BY channel_id, utc_time",
+            "DROP TABLE Measurements",
+            "ALTER TABLE MeasurementsUtc RENAME TO Measurements",
+            "CREATE UNIQUE INDEX MeasurementsChannelTime ON Measurements (channel_id, time)",
+            "UPDATE Channels SET first_seen = {utc:first_seen}, last_seen = {utc:last_seen}",
+            "UPDATE RollupsDirty SET dirty_from = {utc:dirty_from}",
+        ],
+    },
+];
+
+/// Substitute database-specific placeholders of migration statement.
+fn render<DB: Dialect>(statement: &str, blob: &str) -> String {
+    let mut sql = statement.replace("{blob}", blob);
+    while let Some(start) = sql.find("{utc:") {
+        let end = start + sql[start..].find('}').expect("Unclosed placeholder");
+        let column = sql[start + "{utc:".len()..end].to_string();
+        sql.replace_range(start..=end, &DB::utc_timestamp(&column));
+    }
+    sql
+}
+
+/// Version of the latest known schema.
+fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Bring database schema to the latest version.
+///
+/// Each migration is applied in a separate transaction along with its version record.
+pub async fn migrate<DB: Dialect>(conn: &mut DB::Connection) -> Result<(), DbError>
+where
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+
+    usize: ColumnIndex<DB::Row>,
+    for<'q> String: Type<DB> + Encode<'q, DB>,
+    for<'q> i64: Type<DB> + Encode<'q, DB> + Decode<'q, DB>,
+    for<'q> Vec<u8>: Type<DB>,
+{
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS SchemaMigrations (version BIGINT PRIMARY KEY, description VARCHAR)",
+    )
+    .execute(&mut *conn)
+    .await?;
+    let current: i64 = sqlx::query("SELECT MAX(version) FROM SchemaMigrations")
+        .fetch_one(&mut *conn)
+        .await?
+        .try_get::<Option<i64>, _>(0)?
+        .unwrap_or(0);
+
+    let latest = latest_version();
+    if current > latest {
+        return Err(DbError::UnsupportedSchema {
+            found: current,
+            latest,
+        });
+    }
+
+    let blob = <Vec<u8> as Type<DB>>::type_info().name().to_string();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let mut tx = conn.begin().await?;
+        for statement in migration.statements {
+            sqlx::query(&render::<DB>(statement, &blob))
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query::<DB>("INSERT INTO SchemaMigrations (version, description) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.description.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        log::info!(
+            "Database migrated to version {}: {}",
+            migration.version,
+            migration.description
+        );
+    }
+    Ok(())
+}
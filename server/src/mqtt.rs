@@ -0,0 +1,317 @@
+use rtherm_common::{ChannelId, Measurements};
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{task::spawn, time::sleep};
+
+use crate::{config::MqttConfig, recepient::Recepient, retry::Backoff};
+
+/// Number of messages waiting to be sent to broker.
+const REQUEST_CAPACITY: usize = 1024;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Recepient that publishes channel values to MQTT broker.
+///
+/// Values are published to `<prefix>/<channel>/state`,
+/// availability of server is published to `<prefix>/status` and set to `offline` by last will.
+/// Optionally, Home Assistant discovery configs are published for each channel.
+///
+/// Messages are dropped when broker is not available for a long time,
+/// so that updates of other recepients are not delayed.
+pub struct Mqtt {
+    client: AsyncClient,
+    config: MqttConfig,
+    qos: QoS,
+    /// Time of the last published point of each channel
+    last_times: HashMap<ChannelId, SystemTime>,
+    /// Channels with discovery config published during current connection
+    announced: Arc<Mutex<HashSet<ChannelId>>>,
+}
+
+impl Mqtt {
+    pub fn new(config: MqttConfig) -> Result<Self, String> {
+        let qos = rumqttc::qos(config.qos).map_err(|e| format!("Invalid MQTT QoS: {e:?}"))?;
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs_f64(config.keep_alive));
+        options.set_last_will(LastWill::new(
+            Self::status_topic(&config),
+            OFFLINE,
+            qos,
+            true,
+        ));
+        if let Some(user) = &config.user {
+            options.set_credentials(user, config.password.clone().unwrap_or_default());
+        }
+        let (client, eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let this = Self {
+            client,
+            config,
+            qos,
+            last_times: HashMap::new(),
+            announced: Arc::default(),
+        };
+        spawn(this.connection(eventloop));
+        Ok(this)
+    }
+
+    fn status_topic(config: &MqttConfig) -> String {
+        format!("{}/status", config.topic_prefix)
+    }
+    fn state_topic(&self, channel: &ChannelId) -> String {
+        format!("{}/{channel}/state", self.config.topic_prefix)
+    }
+
+    /// Drive connection to broker, reconnecting with exponential backoff.
+    fn connection(&self, mut eventloop: EventLoop) -> impl Future<Output = ()> {
+        let client = self.client.clone();
+        let status_topic = Self::status_topic(&self.config);
+        let qos = self.qos;
+        let announced = self.announced.clone();
+        async move {
+            let mut backoff = Backoff::reconnect();
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(..))) => {
+                        log::info!("MQTT broker connected");
+                        backoff.reset();
+                        // Broker could lose retained messages, so announce channels again.
+                        announced.lock().unwrap().clear();
+                        if let Err(e) = client.try_publish(&status_topic, qos, true, ONLINE) {
+                            log::error!("Cannot publish MQTT status: {e}");
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        log::error!("MQTT connection error: {e}, reconnecting in {delay:?}");
+                        sleep(delay).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publish Home Assistant discovery config for channel.
+    fn announce(&self, channel: &ChannelId) -> Result<(), ClientError> {
+        let unique_id = format!("{}_{channel}", self.config.topic_prefix.replace('/', "_"));
+        let config = json!({
+            "name": channel.to_string(),
+            "unique_id": unique_id,
+            "object_id": unique_id,
+            "state_topic": self.state_topic(channel),
+            "availability_topic": Self::status_topic(&self.config),
+            "device_class": "temperature",
+            "state_class": "measurement",
+            "unit_of_measurement": "°C",
+            "device": {
+                "identifiers": [self.config.client_id],
+                "name": "RTherm",
+            },
+        });
+        self.client.try_publish(
+            format!("{}/sensor/{unique_id}/config", self.config.discovery_prefix),
+            self.qos,
+            true,
+            config.to_string(),
+        )
+    }
+}
+
+impl Recepient for Mqtt {
+    type Error = ClientError;
+
+    async fn update(&mut self, meas: Measurements) -> Vec<Self::Error> {
+        let mut errors = Vec::new();
+        for (channel, mut points) in meas {
+            if self.config.discovery && !self.announced.lock().unwrap().contains(&channel) {
+                match self.announce(&channel) {
+                    Ok(()) => {
+                        self.announced.lock().unwrap().insert(channel.clone());
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+
+            // Points that are older than already published would overwrite retained value.
+            if let Some(last_time) = self.last_times.get(&channel) {
+                points.retain(|p| *last_time < p.time);
+            }
+            points.sort_by_key(|p| p.time);
+
+            let topic = self.state_topic(&channel);
+            for point in points {
+                match self.client.try_publish(
+                    &topic,
+                    self.qos,
+                    self.config.retain,
+                    format!("{:.2}", point.value),
+                ) {
+                    // Points that are not published yet will be accepted next time.
+                    Ok(()) => {
+                        self.last_times.insert(channel.clone(), point.time);
+                    }
+                    Err(e) => {
+                        // The rest of points would fail the same way.
+                        errors.push(e);
+                        break;
+                    }
+                }
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rtherm_common::Point;
+    use rumqttc::{ConnAck, ConnectReturnCode, PingResp, PubAck, Publish};
+    use std::time::UNIX_EPOCH;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+        time::timeout,
+    };
+
+    /// Start mock broker that accepts a single client, returns its port and published messages.
+    async fn start() -> (u16, mpsc::UnboundedReceiver<Publish>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut input = BytesMut::new();
+            loop {
+                let packet = match rumqttc::read(&mut input, 1 << 20) {
+                    Ok(packet) => packet,
+                    Err(rumqttc::Error::InsufficientBytes(..)) => {
+                        if stream.read_buf(&mut input).await.unwrap() == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("Invalid packet: {e:?}"),
+                };
+                let mut output = BytesMut::new();
+                match packet {
+                    rumqttc::Packet::Connect(..) => {
+                        ConnAck::new(ConnectReturnCode::Success, false).write(&mut output)
+                    }
+                    rumqttc::Packet::Publish(publish) => {
+                        let ack = PubAck::new(publish.pkid).write(&mut output);
+                        sender.send(publish).unwrap();
+                        ack
+                    }
+                    rumqttc::Packet::PingReq => PingResp.write(&mut output),
+                    packet => panic!("Unexpected packet: {packet:?}"),
+                }
+                .unwrap();
+                stream.write_all(&output).await.unwrap();
+            }
+        });
+        (port, receiver)
+    }
+
+    async fn next(receiver: &mut mpsc::UnboundedReceiver<Publish>) -> (String, String, bool) {
+        let publish = timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("No message published")
+            .unwrap();
+        (
+            publish.topic,
+            String::from_utf8(publish.payload.to_vec()).unwrap(),
+            publish.retain,
+        )
+    }
+
+    fn measurements(points: &[(f64, u64)]) -> Measurements {
+        let points = points
+            .iter()
+            .map(|&(value, secs)| Point {
+                value,
+                time: UNIX_EPOCH + Duration::from_secs(secs),
+            })
+            .collect();
+        Measurements::from_iter([(ChannelId::try_from("a").unwrap(), points)])
+    }
+
+    fn config(port: u16) -> MqttConfig {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            client_id: "test".to_string(),
+            user: None,
+            password: None,
+            topic_prefix: "home/temp".to_string(),
+            qos: 1,
+            retain: true,
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            keep_alive: 60.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_status_discovery_and_new_values() {
+        let (port, mut published) = start().await;
+        let mut mqtt = Mqtt::new(config(port)).unwrap();
+        assert_eq!(
+            next(&mut published).await,
+            ("home/temp/status".to_string(), ONLINE.to_string(), true)
+        );
+
+        let errors = mqtt.update(measurements(&[(2.0, 20), (1.0, 10)])).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        let (topic, config, _) = next(&mut published).await;
+        assert_eq!(topic, "homeassistant/sensor/home_temp_a/config");
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["state_topic"], "home/temp/a/state");
+        for value in ["1.00", "2.00"] {
+            assert_eq!(
+                next(&mut published).await,
+                ("home/temp/a/state".to_string(), value.to_string(), true)
+            );
+        }
+
+        // Older point must not overwrite retained value.
+        let errors = mqtt.update(measurements(&[(3.0, 15), (4.0, 30)])).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(
+            next(&mut published).await,
+            ("home/temp/a/state".to_string(), "4.00".to_string(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_point_is_not_marked_published() {
+        // Event loop is not polled, so only a single request fits into client.
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "127.0.0.1", 1), 1);
+        let mut mqtt = Mqtt {
+            client,
+            config: MqttConfig {
+                discovery: false,
+                ..config(1)
+            },
+            qos: QoS::AtLeastOnce,
+            last_times: HashMap::new(),
+            announced: Arc::default(),
+        };
+        let errors = mqtt.update(measurements(&[(1.0, 10), (2.0, 20)])).await;
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        let channel = ChannelId::try_from("a").unwrap();
+        assert_eq!(
+            mqtt.last_times.get(&channel),
+            Some(&(UNIX_EPOCH + Duration::from_secs(10)))
+        );
+    }
+}
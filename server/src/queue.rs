@@ -0,0 +1,367 @@
+use rtherm_common::Measurements;
+use serde::Serialize;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::spawn,
+    time::sleep,
+};
+
+use crate::{
+    recepient::Recepient,
+    retry::Backoff,
+    wal::{Seq, Wal},
+};
+
+/// Counters of recepient worker.
+#[derive(Clone, Default, Debug, Serialize)]
+pub struct WorkerStats {
+    /// Number of processed updates
+    pub processed: u64,
+    /// Number of errors reported by recepient
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// Status of recepient queue as reported by HTTP API.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueStatus {
+    pub name: String,
+    /// Number of updates waiting in queue
+    pub depth: usize,
+    pub capacity: usize,
+    #[serde(flatten)]
+    pub stats: WorkerStats,
+}
+
+/// Accepted measurements along with their position in write-ahead log.
+struct Entry {
+    seq: Option<Seq>,
+    measurements: Measurements,
+}
+
+/// Recepient fed through a bounded queue by a separate worker task.
+pub struct RecepientQueue {
+    name: String,
+    /// Recepient replays write-ahead log
+    logged: bool,
+    sender: mpsc::Sender<Entry>,
+    stats: Arc<Mutex<WorkerStats>>,
+}
+
+/// Number of retries of failed update from write-ahead log before it is dropped
+const MAX_RETRIES: u32 = 3;
+
+struct Worker<R: Recepient> {
+    name: String,
+    recepient: R,
+    stats: Arc<Mutex<WorkerStats>>,
+    wal: Option<Arc<Wal>>,
+}
+
+impl<R: Recepient> Worker<R> {
+    /// Update recepient, returns whether it succeeded.
+    async fn update(&mut self, meas: Measurements) -> bool {
+        let errors = self.recepient.update(meas).await;
+        let mut stats = self.stats.lock().unwrap();
+        stats.processed += 1;
+        let ok = errors.is_empty();
+        for err in errors {
+            log::error!("Recepient {} update error: {err}", self.name);
+            stats.errors += 1;
+            stats.last_error = Some(err.to_string());
+        }
+        ok
+    }
+
+    async fn process(&mut self, seq: Option<Seq>, meas: Measurements) {
+        let (Some(wal), Some(seq)) = (self.wal.clone(), seq) else {
+            self.update(meas).await;
+            return;
+        };
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let mut attempt = 0;
+        while !self.update(meas.clone()).await {
+            if attempt == MAX_RETRIES {
+                // Holding checkpoint back would stop removal of log segments.
+                log::error!(
+                    "Recepient {} failed to process request {seq}, dropping it",
+                    self.name
+                );
+                break;
+            }
+            attempt += 1;
+            sleep(backoff.next_delay()).await;
+        }
+        if let Err(e) = wal.checkpoint(&self.name, seq).await {
+            log::error!("Cannot checkpoint recepient {}: {e}", self.name);
+        }
+    }
+
+    async fn run(mut self, mut receiver: mpsc::Receiver<Entry>) {
+        // Requests accepted before restart but not processed by recepient.
+        let mut replayed = None;
+        if let Some(wal) = self.wal.clone() {
+            match wal.pending(&self.name).await {
+                Ok(records) => {
+                    if !records.is_empty() {
+                        log::info!(
+                            "Replaying {} requests to recepient {}",
+                            records.len(),
+                            self.name
+                        );
+                    }
+                    for (seq, request) in records {
+                        self.process(Some(seq), request.measurements).await;
+                        replayed = Some(seq);
+                    }
+                }
+                Err(e) => log::error!(
+                    "Cannot read write-ahead log for recepient {}: {e}",
+                    self.name
+                ),
+            }
+        }
+
+        while let Some(entry) = receiver.recv().await {
+            if entry
+                .seq
+                .zip(replayed)
+                .is_some_and(|(seq, last)| seq <= last)
+            {
+                continue;
+            }
+            self.process(entry.seq, entry.measurements).await;
+        }
+    }
+}
+
+impl RecepientQueue {
+    /// Spawn worker for `recepient`.
+    ///
+    /// If `wal` is provided then requests that were not processed before restart are replayed first,
+    /// so it must not be provided for recepients that cannot tolerate duplicates, e.g. notifiers.
+    pub fn spawn<R: Recepient + Send + 'static>(
+        name: impl Into<String>,
+        recepient: R,
+        capacity: usize,
+        wal: Option<Arc<Wal>>,
+    ) -> Self {
+        let name = name.into();
+        let (sender, receiver) = mpsc::channel(capacity);
+        let stats = Arc::new(Mutex::new(WorkerStats::default()));
+        let logged = wal.is_some();
+        let worker = Worker {
+            name: name.clone(),
+            recepient,
+            stats: stats.clone(),
+            wal,
+        };
+        spawn(worker.run(receiver));
+        Self {
+            name,
+            logged,
+            sender,
+            stats,
+        }
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            name: self.name.clone(),
+            depth: self.sender.max_capacity() - self.sender.capacity(),
+            capacity: self.sender.max_capacity(),
+            stats: self.stats.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Measurements cannot be put into queue of recepient.
+#[derive(Clone, Debug)]
+pub enum QueueError {
+    /// Queue is full, try again later.
+    Full(String),
+    /// Worker of recepient has stopped.
+    Closed(String),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Full(name) => write!(f, "Queue of recepient {name} is full"),
+            QueueError::Closed(name) => write!(f, "Worker of recepient {name} has stopped"),
+        }
+    }
+}
+impl Error for QueueError {}
+
+/// Queues of all recepients.
+#[derive(Default)]
+pub struct Pipeline {
+    queues: Vec<RecepientQueue>,
+}
+
+impl Pipeline {
+    pub fn push(&mut self, queue: RecepientQueue) {
+        self.queues.push(queue);
+    }
+
+    /// Reserve place in queue of each recepient.
+    ///
+    /// Measurements are either put into all queues or into none of them.
+    /// Reservation does not borrow pipeline, so it can be held across awaits.
+    pub fn reserve(&self) -> Result<Reservation, QueueError> {
+        let permits = self
+            .queues
+            .iter()
+            .map(|queue| {
+                queue
+                    .sender
+                    .clone()
+                    .try_reserve_owned()
+                    .map_err(|e| match e {
+                        TrySendError::Full(_) => QueueError::Full(queue.name.clone()),
+                        TrySendError::Closed(_) => QueueError::Closed(queue.name.clone()),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Reservation { permits })
+    }
+
+    /// Names of recepients that replay write-ahead log.
+    pub fn logged_names(&self) -> impl Iterator<Item = &str> {
+        self.queues
+            .iter()
+            .filter(|queue| queue.logged)
+            .map(|queue| queue.name.as_str())
+    }
+
+    pub fn status(&self) -> Vec<QueueStatus> {
+        self.queues.iter().map(RecepientQueue::status).collect()
+    }
+}
+
+/// Reserved place in queues of all recepients.
+pub struct Reservation {
+    permits: Vec<mpsc::OwnedPermit<Entry>>,
+}
+
+impl Reservation {
+    pub fn send(self, seq: Option<Seq>, meas: Measurements) {
+        for permit in self.permits {
+            permit.send(Entry {
+                seq,
+                measurements: meas.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WalConfig;
+    use rtherm_common::{ChannelId, Point, ProvideRequest};
+    use std::{io, path::Path, time::SystemTime};
+    use tokio::fs;
+
+    /// Recepient that records received values and fails on negative ones.
+    struct Mock(Arc<Mutex<Vec<f64>>>);
+
+    impl Recepient for Mock {
+        type Error = io::Error;
+        async fn update(&mut self, meas: Measurements) -> Vec<io::Error> {
+            let value = meas.values().flatten().next().unwrap().value;
+            self.0.lock().unwrap().push(value);
+            if value < 0.0 {
+                vec![io::Error::other("negative value")]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    fn request(value: f64) -> ProvideRequest {
+        ProvideRequest::new(Measurements::from_iter([(
+            ChannelId::try_from("a").unwrap(),
+            vec![Point {
+                value,
+                time: SystemTime::now(),
+            }],
+        )]))
+    }
+
+    async fn open(dir: &Path) -> Arc<Wal> {
+        let config = WalConfig {
+            path: dir.to_str().unwrap().to_string(),
+            // Each record is in a separate segment.
+            segment_size: 1,
+        };
+        Arc::new(Wal::open(&config).await.unwrap())
+    }
+
+    async fn segments(dir: &Path) -> usize {
+        let mut count = 0;
+        let mut entries = fs::read_dir(dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            count += entry.path().extension().is_some_and(|ext| ext == "wal") as usize;
+        }
+        count
+    }
+
+    async fn received(values: &Mutex<Vec<f64>>, len: usize) -> Vec<f64> {
+        while values.lock().unwrap().len() < len {
+            sleep(Duration::from_millis(10)).await;
+        }
+        values.lock().unwrap().clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_update_is_checkpointed_and_not_replayed() {
+        let dir = std::env::temp_dir().join(format!("rtherm-queue-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+
+        let wal = open(&dir).await;
+        let values = Arc::default();
+        let mut pipeline = Pipeline::default();
+        pipeline.push(RecepientQueue::spawn(
+            "mock",
+            Mock(Arc::clone(&values)),
+            4,
+            Some(wal.clone()),
+        ));
+        for value in [1.0, -1.0, 2.0] {
+            let reservation = pipeline.reserve().unwrap();
+            let request = request(value);
+            let seq = wal.append(None, &request, |_| ()).await.unwrap();
+            reservation.send(Some(seq), request.measurements);
+        }
+        let mut expected = vec![1.0];
+        expected.extend([-1.0; 1 + MAX_RETRIES as usize]);
+        expected.push(2.0);
+        assert_eq!(received(&values, expected.len()).await, expected);
+        // Wait for the last checkpoint.
+        while segments(&dir).await > 1 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        // Request is logged but not processed before restart.
+        wal.append(None, &request(3.0), |_| ()).await.unwrap();
+        drop(pipeline);
+        drop(wal);
+
+        let wal = open(&dir).await;
+        let values = Arc::default();
+        let _queue = RecepientQueue::spawn("mock", Mock(Arc::clone(&values)), 4, Some(wal));
+        assert_eq!(received(&values, 1).await, [3.0]);
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(*values.lock().unwrap(), [3.0]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+use std::{fmt::Display, future::Future};
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+use tokio::time::sleep;
+
+/// Exponentially growing delay between attempts.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            delay: min,
+        }
+    }
+
+    /// Backoff between retries of a failed request.
+    #[cfg(any(feature = "influxdb", feature = "webhook"))]
+    pub fn request() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(10))
+    }
+
+    /// Backoff between attempts to reconnect to a server.
+    pub fn reconnect() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = self.min;
+    }
+
+    /// Delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (2 * delay).min(self.max);
+        delay
+    }
+}
+
+/// Error that could disappear if operation is repeated.
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+impl Transient for reqwest::Error {
+    fn is_transient(&self) -> bool {
+        !self.is_builder()
+    }
+}
+
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+impl Transient for reqwest::StatusCode {
+    fn is_transient(&self) -> bool {
+        self.is_server_error() || *self == reqwest::StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+/// Run `op` retrying transient errors at most `max_retries` times with exponential backoff.
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+pub async fn with_retries<T, E: Transient + Display, F: Future<Output = Result<T, E>>>(
+    max_retries: u32,
+    mut op: impl FnMut() -> F,
+) -> Result<T, E> {
+    let mut backoff = Backoff::request();
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => break Ok(value),
+            Err(e) if e.is_transient() && attempt < max_retries => {
+                attempt += 1;
+                let delay = backoff.next_delay();
+                log::warn!("{e}, retrying in {delay:?} ({attempt}/{max_retries})");
+                sleep(delay).await;
+            }
+            Err(e) => break Err(e),
+        }
+    }
+}
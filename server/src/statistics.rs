@@ -4,6 +4,7 @@ use serde::Serialize;
 use std::{
     collections::{HashMap, VecDeque},
     fmt::{self, Display},
+    ops::RangeInclusive,
     time::{Duration, SystemTime},
 };
 
@@ -50,6 +51,25 @@ impl ChannelHistory {
         }
     }
 
+    /// Change of value over `window` ending at the last point.
+    ///
+    /// Returns `None` if there are less than two points in window.
+    pub fn change(&self, window: Duration) -> Option<f64> {
+        let last = self.window.back()?;
+        let start = last.time.checked_sub(window)?;
+        let first = self.window[self.window.partition_point(|p| p.time < start)];
+        if first.time < last.time {
+            Some(last.value - first.value)
+        } else {
+            None
+        }
+    }
+
+    /// Number of points in memory.
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
     pub fn statistics(&self) -> ChannelStatistics {
         let (sum, min, max) = self.window.iter().copied().fold(
             (0.0, f64::INFINITY, f64::NEG_INFINITY),
@@ -96,3 +116,28 @@ impl Display for ChannelStatistics {
         Ok(())
     }
 }
+
+pub trait RangeExt {
+    type Item: Copy;
+    fn widen(&self, offset: Self::Item) -> Self;
+    fn display(&self) -> String;
+}
+
+impl RangeExt for RangeInclusive<f64> {
+    type Item = f64;
+    fn widen(&self, offset: f64) -> Self {
+        if self.start() - 2.0 * offset > *self.end() {
+            let center = 0.5 * (self.start() + self.end());
+            center..=center
+        } else {
+            (self.start() - offset)..=(self.end() + offset)
+        }
+    }
+    fn display(&self) -> String {
+        if self.start() == self.end() {
+            format!("{}", self.start())
+        } else {
+            format!("[{}, {}]", self.start(), self.end())
+        }
+    }
+}
@@ -9,11 +9,13 @@ use std::{
     ops::{Deref, DerefMut},
     path::PathBuf,
     pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
     fs::{try_exists, File},
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
 type StorageReadResult<E> = Result<Option<Vec<u8>>, E>;
@@ -122,51 +124,51 @@ impl<S: Storage<Error: Send + 'static> + Send + Sync + 'static> DynStorage for S
     }
 }
 
-pub struct AnyStorage(Box<dyn DynStorage>);
+/// Type-erased storage that can be shared between its users.
+#[derive(Clone)]
+pub struct AnyStorage(Arc<Mutex<dyn DynStorage>>);
 
 impl AnyStorage {
     pub fn new<S: Storage<Error: Send + 'static> + Send + Sync + 'static>(storage: S) -> Self {
-        Self(Box::new(storage))
+        Self(Arc::new(Mutex::new(storage)))
     }
 }
 
 impl Storage for AnyStorage {
     type Error = AnyError;
     async fn load(&mut self, name: String) -> StorageReadResult<Self::Error> {
-        self.0.load_dyn(name).await
+        self.0.lock().await.load_dyn(name).await
     }
     async fn store(&mut self, name: String, value: Vec<u8>) -> Result<(), Self::Error> {
-        self.0.store_dyn(name, value).await
+        self.0.lock().await.store_dyn(name, value).await
     }
 }
 
+/// Period of retrying to load a value that failed to load.
+const RELOAD_PERIOD: Duration = Duration::from_secs(10);
+
 pub struct Stored<T: Serialize + for<'de> Deserialize<'de>, S: Storage> {
     name: String,
     storage: S,
     value: T,
+    /// Value is in sync with storage.
+    /// Until then it is not dumped to avoid overwriting stored value.
+    loaded: bool,
+    /// Time of the last failed load.
+    failed_at: Option<Instant>,
 }
 
 impl<T: Serialize + for<'de> Deserialize<'de>, S: Storage> Stored<T, S> {
-    pub async fn load_or(name: String, mut storage: S, value: T) -> Self {
-        let value = match storage.load(name.clone()).await {
-            Ok(Some(data)) => match serde_json::from_slice(&data) {
-                Ok(state) => state,
-                Err(e) => {
-                    log::error!("Error deserializing value: {e}");
-                    value
-                }
-            },
-            Ok(None) => value,
-            Err(e) => {
-                log::error!("Error reading from storage: {}", e);
-                value
-            }
-        };
-        Self {
+    pub async fn load_or(name: String, storage: S, value: T) -> Self {
+        let mut this = Self {
             name,
             storage,
             value,
-        }
+            loaded: false,
+            failed_at: None,
+        };
+        this.load().await;
+        this
     }
 
     pub async fn load_or_default(name: String, storage: S) -> Self
@@ -176,7 +178,48 @@ impl<T: Serialize + for<'de> Deserialize<'de>, S: Storage> Stored<T, S> {
         Self::load_or(name, storage, T::default()).await
     }
 
+    /// Replace value with the stored one if any.
+    async fn load(&mut self) {
+        match self.storage.load(self.name.clone()).await {
+            Ok(Some(data)) => match serde_json::from_slice(&data) {
+                Ok(state) => {
+                    if self.failed_at.is_some() {
+                        log::warn!(
+                            "{} is loaded, changes made while storage was unavailable are discarded",
+                            self.name
+                        );
+                    }
+                    self.value = state;
+                }
+                Err(e) => log::error!("Error deserializing value: {e}"),
+            },
+            Ok(None) => (),
+            Err(e) => {
+                log::error!("Error reading {} from storage: {}", self.name, e);
+                self.failed_at = Some(Instant::now());
+                return;
+            }
+        }
+        self.loaded = true;
+    }
+
+    /// Retry to load value if it has not been loaded yet.
+    async fn ensure_loaded(&mut self) -> bool {
+        if !self.loaded
+            && self
+                .failed_at
+                .is_none_or(|time| time.elapsed() >= RELOAD_PERIOD)
+        {
+            self.load().await;
+        }
+        self.loaded
+    }
+
     pub async fn dump(&mut self) {
+        if !self.ensure_loaded().await {
+            log::warn!("{} is not loaded yet, skip writing it", self.name);
+            return;
+        }
         match serde_json::to_vec(&self.value) {
             Ok(data) => {
                 if let Err(e) = self.storage.store(self.name.clone(), data).await {
@@ -195,12 +238,22 @@ impl<T: Serialize + for<'de> Deserialize<'de>, S: Storage> StoredLock<T, S> {
         Self(RwLock::new(inner))
     }
 
-    pub async fn read(&self) -> RwLockReadGuard<T> {
-        RwLockReadGuard::map(self.0.read().await, |s| &s.value)
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let guard = self.0.read().await;
+        let guard = if guard.loaded {
+            guard
+        } else {
+            drop(guard);
+            self.0.write().await.ensure_loaded().await;
+            self.0.read().await
+        };
+        RwLockReadGuard::map(guard, |s| &s.value)
     }
-    pub async fn write(&self) -> StoredLockWriteGuard<T, S> {
+    pub async fn write(&self) -> StoredLockWriteGuard<'_, T, S> {
+        let mut inner = self.0.write().await;
+        inner.ensure_loaded().await;
         StoredLockWriteGuard {
-            inner: self.0.write().await,
+            inner,
             dumped: false,
         }
     }
@@ -1,7 +1,10 @@
 use crate::{
+    alerting::{
+        display_window, Ack, AlertEvent, AlertKind, Escalation, Notifier, Rule, Subscription,
+    },
     config::TelegramConfig,
     recepient::Recepient,
-    statistics::ChannelHistory,
+    statistics::{ChannelHistory, RangeExt},
     storage::{Storage, Stored, StoredLock},
 };
 use frankenstein::{
@@ -11,91 +14,26 @@ use frankenstein::{
 use rtherm_common::{ChannelId, Measurements, Point};
 use serde::{Deserialize, Serialize};
 use std::{
-    borrow::Cow,
     collections::{hash_map::Entry, HashMap},
     fmt::Write,
-    ops::RangeInclusive,
     str::FromStr,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::{mpsc, oneshot, RwLock},
+    task::spawn,
 };
-use tokio::{sync::RwLock, task::spawn, time::sleep};
 
 type ChatId = i64;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct CommonSettings {
-    /// Time to assume that channel is offline.
-    offline_timeout: Duration,
-    /// Offset from normal range bound when value becomes normal again.
-    hysteresis: f64,
-}
-
-pub trait RangeExt {
-    type Item: Copy;
-    fn widen(&self, offset: Self::Item) -> Self;
-    fn contains_range(&self, other: &Self) -> bool;
-    fn display(&self) -> String;
-}
-
-impl RangeExt for RangeInclusive<f64> {
-    type Item = f64;
-    fn widen(&self, offset: f64) -> Self {
-        if self.start() - 2.0 * offset > *self.end() {
-            let center = 0.5 * (self.start() + self.end());
-            center..=center
-        } else {
-            (self.start() - offset)..=(self.end() + offset)
-        }
-    }
-    fn contains_range(&self, other: &Self) -> bool {
-        self.start() <= other.start() && other.end() <= self.end()
-    }
-    fn display(&self) -> String {
-        if self.start() == self.end() {
-            format!("{}", self.start())
-        } else {
-            format!("[{}, {}]", self.start(), self.end())
-        }
-    }
-}
-
-impl Default for CommonSettings {
-    fn default() -> Self {
-        Self {
-            offline_timeout: Duration::from_secs(4 * 60),
-            hysteresis: 5.0,
-        }
-    }
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct ChannelSettings {
-    /// Range of good values for a channel.
-    ///
-    /// Values outside of this range considered to be bad.
-    normal_range: RangeInclusive<f64>,
-}
-
-impl Default for ChannelSettings {
-    fn default() -> Self {
-        Self {
-            normal_range: 30.0..=80.0,
-        }
-    }
-}
-
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 struct ChannelSubscription {
-    settings: ChannelSettings,
-    is_bad: bool,
+    settings: Rule,
 }
 
 #[derive(Clone, Default, Debug)]
 struct ChannelState {
     values: ChannelHistory,
-    last_update: Option<Instant>,
-    online: bool,
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
@@ -105,7 +43,6 @@ struct Chat {
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 struct Settings {
-    common: CommonSettings,
     chats: HashMap<ChatId, Chat>,
 }
 
@@ -118,22 +55,8 @@ type SharedSettings<S> = Arc<StoredLock<Settings, S>>;
 type SharedState = Arc<RwLock<State>>;
 
 impl ChannelState {
-    fn update(&mut self, points: impl IntoIterator<Item = Point>) -> bool {
+    fn update(&mut self, points: impl IntoIterator<Item = Point>) {
         self.values.update(points);
-        let becomes_online = !self.online;
-        self.online = true;
-        self.last_update = Some(Instant::now());
-        becomes_online
-    }
-    fn becomes_offline(&mut self, offline_timeout: Duration) -> bool {
-        let mut becomes_offline = false;
-        if let Some(last_update) = self.last_update {
-            if last_update + offline_timeout < Instant::now() && self.online {
-                self.online = false;
-                becomes_offline = true;
-            }
-        }
-        becomes_offline
     }
 
     fn digest(&self) -> String {
@@ -168,6 +91,7 @@ enum Command {
     Digest { channel: Option<ChannelId> },
     Subscribe { channel: Option<ChannelId> },
     Unsubscribe { channel: Option<ChannelId> },
+    Ack { channel: Option<ChannelId> },
 }
 
 impl Command {
@@ -176,6 +100,7 @@ impl Command {
 /digest - show info about all channels or a selected one.
 /subscribe - subscribe to a specified channel.
 /unsubscribe - unsubscribe from a previously subscribed channel.
+/ack - acknowledge alerts of all channels or a selected one to stop reminders.
 "#;
 }
 
@@ -183,13 +108,16 @@ impl FromStr for Command {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = if s.contains('_') {
-            Cow::Owned(s.replace('_', " "))
-        } else {
-            Cow::Borrowed(s)
-        };
         let mut args = s.split_whitespace();
         let cmd = args.next().ok_or("Empty command")?;
+        // Argument can be joined with `_` to make command clickable, e.g. `/ack_<channel>`.
+        // Channel id can contain `_` itself, so only the first one is a separator.
+        // Trailing `_` without argument, e.g. `/ack_`, is the same as no argument.
+        let (cmd, joined) = match cmd.split_once('_') {
+            Some((cmd, arg)) => (cmd, Some(arg).filter(|arg| !arg.is_empty())),
+            None => (cmd, None),
+        };
+        let mut args = joined.into_iter().chain(args);
         if !cmd.starts_with('/') {
             return Err("Command must start with '/'".into());
         }
@@ -211,6 +139,9 @@ impl FromStr for Command {
             "unsubscribe" => Self::Unsubscribe {
                 channel: make_opt_chid(args.next())?,
             },
+            "ack" => Self::Ack {
+                channel: make_opt_chid(args.next())?,
+            },
             other => return Err(format!("Unknown command: {other}")),
         };
         if let Some(extra) = args.next() {
@@ -224,6 +155,11 @@ pub struct Telegram<S: Storage> {
     api: AsyncApi,
     settings: SharedSettings<S>,
     state: SharedState,
+    remind_interval: Option<f64>,
+    escalation: Option<Escalation>,
+    acks: mpsc::UnboundedSender<Ack>,
+    /// Taken by alerting
+    ack_receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<Ack>>>>,
 }
 
 impl<S: Storage> Clone for Telegram<S> {
@@ -232,12 +168,73 @@ impl<S: Storage> Clone for Telegram<S> {
             api: self.api.clone(),
             settings: self.settings.clone(),
             state: self.state.clone(),
+            remind_interval: self.remind_interval,
+            escalation: self.escalation.clone(),
+            acks: self.acks.clone(),
+            ack_receiver: self.ack_receiver.clone(),
         }
     }
 }
 
 type Error = <AsyncApi as AsyncTelegramApi>::Error;
 
+/// Format alert event as HTML message.
+fn alert_message(event: &AlertEvent) -> String {
+    let channel_id = &event.channel;
+    let values = event.values().map(|r| r.display()).unwrap_or_default();
+    // Fields are matched instead of unwrapped, so that malformed event cannot panic.
+    let mut message = match (
+        event.event,
+        event.normal_range(),
+        event.change,
+        event.window,
+        event.limit,
+    ) {
+        (AlertKind::OutOfRange, Some(range), ..) => format!(
+            "<code>{}</code> value {} is out of normal range {:?}.",
+            channel_id, values, range,
+        ),
+        (AlertKind::BackToNormal, Some(range), ..) => format!(
+            "<code>{}</code> value {} returned to normal range {:?}.",
+            channel_id, values, range,
+        ),
+        (AlertKind::RateExceeded, _, Some(change), Some(window), Some(limit)) => format!(
+            "<code>{}</code> value {} by {:.1} in {}, more than {}.",
+            channel_id,
+            if change < 0.0 { "fell" } else { "rose" },
+            change.abs(),
+            display_window(window),
+            limit,
+        ),
+        (AlertKind::RateNormal, _, Some(change), Some(window), _) => format!(
+            "<code>{}</code> value change returned to normal ({:+.1} in {}).",
+            channel_id,
+            change,
+            display_window(window),
+        ),
+        (AlertKind::Offline, ..) => format!("<code>{}</code> is offline", channel_id),
+        (AlertKind::Online, ..) => {
+            format!("<code>{}</code> is online (value: {}).", channel_id, values)
+        }
+        (kind, ..) => {
+            log::warn!("Event {kind} of channel {channel_id} lacks its details: {event:?}");
+            format!("<code>{}</code>: {}", channel_id, event.message)
+        }
+    };
+    if event.event.is_bad() {
+        let header = match (event.escalated, event.reminder) {
+            (true, _) => "<b>Escalation!</b> Alert is not acknowledged.",
+            (false, 0) => "<b>Alert!</b>",
+            (false, _) => "<b>Reminder!</b> Alert is not resolved yet.",
+        };
+        message = format!("{header}\n{message}");
+    }
+    if matches!(event.event, AlertKind::OutOfRange | AlertKind::RateExceeded) {
+        write!(message, "\n/ack_{channel_id} to stop reminders").unwrap();
+    }
+    message
+}
+
 async fn send_message(api: &AsyncApi, chat: ChatId, text: impl Into<String>) -> Result<(), Error> {
     api.send_message(
         &SendMessageParams::builder()
@@ -253,13 +250,20 @@ async fn send_message(api: &AsyncApi, chat: ChatId, text: impl Into<String>) ->
 impl<S: Storage + Sync + Send + 'static> Telegram<S> {
     pub async fn new(config: TelegramConfig, storage: S) -> Self {
         let settings = Stored::load_or_default("telegram-state".to_string(), storage).await;
+        let (acks, ack_receiver) = mpsc::unbounded_channel();
         let this = Self {
             api: AsyncApi::new(&config.token),
             settings: Arc::new(StoredLock::new(settings)),
             state: SharedState::default(),
+            remind_interval: config.remind_interval,
+            escalation: config.escalation.map(|escalation| Escalation {
+                timeout: escalation.timeout,
+                targets: escalation.chats.iter().map(|id| id.to_string()).collect(),
+            }),
+            acks,
+            ack_receiver: Arc::new(Mutex::new(Some(ack_receiver))),
         };
         spawn(this.clone().poll());
-        spawn(this.clone().monitor());
         this
     }
 
@@ -412,128 +416,174 @@ impl<S: Storage + Sync + Send + 'static> Telegram<S> {
                     .await?
                 }
             }
-        }
-        Ok(())
-    }
-
-    async fn monitor(self) -> ! {
-        let settings = self.settings.read().await.common.clone();
-
-        loop {
-            sleep(settings.offline_timeout / 2).await;
-
-            let mut messages = Vec::<(ChatId, String)>::new();
-
-            {
-                let mut state = self.state.write().await;
-                for (channel_id, channel) in state.channels.iter_mut() {
-                    if channel.becomes_offline(settings.offline_timeout) {
-                        let chats = self.settings.read().await.chats.clone();
-                        for (chat_id, chat) in chats.into_iter() {
-                            if chat.subscriptions.contains_key(channel_id) {
-                                messages.push((
-                                    chat_id,
-                                    format!(
-                                        "<b>Alert!</b>\n<code>{}</code> is offline",
-                                        channel_id
-                                    ),
-                                ));
-                            }
+            Command::Ack { channel } => {
+                let (reply, count) = oneshot::channel();
+                let ack = Ack {
+                    target: chat_id.to_string(),
+                    channel,
+                    reply,
+                };
+                let count = match self.acks.send(ack) {
+                    Ok(()) => count.await.ok(),
+                    Err(_) => None,
+                };
+                send_message(
+                    &self.api,
+                    chat_id,
+                    match count {
+                        None => "Error: Alerting is not running.".to_string(),
+                        Some(0) => "There are no alerts to acknowledge.".to_string(),
+                        Some(n) => {
+                            format!("{n} alert(s) acknowledged, no more reminders will be sent.")
                         }
-                    }
-                }
-            }
-
-            for (chat_id, message) in messages {
-                if let Err(err) = send_message(&self.api, chat_id, message).await {
-                    log::error!("Cannot send message: {err}");
-                }
+                    },
+                )
+                .await?
             }
         }
+        Ok(())
     }
 }
 
+/// Updates channel values for digests, alerts are sent as [`Notifier`].
 impl<S: Storage + Sync + Send + 'static> Recepient for Telegram<S> {
     type Error = Error;
 
     async fn update(&mut self, measurements: Measurements) -> Vec<Error> {
-        let mut messages = Vec::<(ChatId, String)>::new();
+        let mut state = self.state.write().await;
+        for (channel_id, points) in measurements {
+            state.channels.entry(channel_id).or_default().update(points);
+        }
+        Vec::new()
+    }
+}
 
-        {
-            let mut state = self.state.write().await;
-            let mut settings = self.settings.write().await;
-            let common_settings = settings.common.clone();
+/// Each chat is a target, subscribed to channels by commands.
+///
+/// Reminders and escalation from config apply to subscriptions that do not set their own.
+impl<S: Storage + Sync + Send + 'static> Notifier for Telegram<S> {
+    type Error = Error;
 
-            for (channel_id, points) in measurements {
-                if points.is_empty() {
-                    continue;
-                }
-                let value_range = points
-                    .iter()
-                    .map(|p| p.value)
-                    .fold(f64::INFINITY..=f64::NEG_INFINITY, |range, value| {
-                        range.start().min(value)..=range.end().max(value)
-                    });
-
-                let channel = state.channels.entry(channel_id.clone()).or_default();
-                let becomes_online = channel.update(points);
-
-                for (&chat_id, chat) in settings.chats.iter_mut() {
-                    if let Some(sub) = chat.subscriptions.get_mut(&channel_id) {
-                        if becomes_online {
-                            messages.push((
-                                chat_id,
-                                format!(
-                                    "<code>{}</code> is online (value: {}).",
-                                    channel_id,
-                                    value_range.display(),
-                                ),
-                            ));
-                        }
-                        if !sub.is_bad {
-                            if !&sub.settings.normal_range.contains_range(&value_range) {
-                                sub.is_bad = true;
-                                messages.push((
-                                    chat_id,
-                                    format!(
-                                        "<b>Alert!</b>\n<code>{}</code> value {} is out of normal range {:?}.",
-                                        channel_id,
-                                        value_range.display(),
-                                        sub.settings.normal_range,
-                                    ),
-                                ));
-                            }
-                        } else if sub
-                            .settings
-                            .normal_range
-                            .widen(-common_settings.hysteresis)
-                            .contains_range(&value_range)
-                        {
-                            sub.is_bad = false;
-                            messages.push((
-                                chat_id,
-                                format!(
-                                    "<code>{}</code> value {} returned to normal range {:?}.",
-                                    channel_id,
-                                    value_range.display(),
-                                    sub.settings.normal_range,
-                                ),
-                            ));
-                        }
+    async fn subscriptions(&self, channel: ChannelId) -> Vec<Subscription> {
+        self.settings
+            .read()
+            .await
+            .chats
+            .iter()
+            .filter_map(|(chat_id, chat)| {
+                chat.subscriptions.get(&channel).map(|sub| {
+                    let mut rule = sub.settings.clone();
+                    rule.remind_interval = rule.remind_interval.or(self.remind_interval);
+                    rule.escalation = rule.escalation.or_else(|| self.escalation.clone());
+                    Subscription {
+                        target: chat_id.to_string(),
+                        rule,
                     }
-                }
+                })
+            })
+            .collect()
+    }
+
+    async fn notify(&self, target: String, event: AlertEvent) -> Result<(), Self::Error> {
+        let chat_id: ChatId = match target.parse() {
+            Ok(id) => id,
+            Err(_) => {
+                log::error!("Invalid chat id: {target}");
+                return Ok(());
             }
+        };
+        send_message(&self.api, chat_id, alert_message(&event)).await
+    }
 
-            settings.async_drop().await
+    fn acknowledgements(&self) -> Option<mpsc::UnboundedReceiver<Ack>> {
+        self.ack_receiver.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joined_argument_can_contain_underscore() {
+        let channel = ChannelId::try_from("boiler_out_1").unwrap();
+        assert!(matches!(
+            Command::from_str("/ack_boiler_out_1"),
+            Ok(Command::Ack { channel: Some(c) }) if c == channel
+        ));
+        assert!(matches!(
+            Command::from_str("/digest boiler_out_1"),
+            Ok(Command::Digest { channel: Some(c) }) if c == channel
+        ));
+        assert!(matches!(
+            Command::from_str("/ack"),
+            Ok(Command::Ack { channel: None })
+        ));
+        assert!(Command::from_str("/ack_a b").is_err());
+    }
+
+    #[test]
+    fn empty_joined_argument_is_none() {
+        assert!(matches!(
+            Command::from_str("/ack_"),
+            Ok(Command::Ack { channel: None })
+        ));
+        assert!(matches!(
+            Command::from_str("/digest_"),
+            Ok(Command::Digest { channel: None })
+        ));
+        assert!(matches!(
+            Command::from_str("/subscribe_ boiler_in"),
+            Ok(Command::Subscribe { channel: Some(c) }) if *c == *"boiler_in"
+        ));
+        assert!(Command::from_str("/unknown_").is_err());
+    }
+
+    fn event(kind: AlertKind) -> AlertEvent {
+        AlertEvent {
+            event: kind,
+            channel: ChannelId::try_from("boiler").unwrap(),
+            min: None,
+            max: None,
+            normal_range: None,
+            change: None,
+            window: None,
+            limit: None,
+            time: 0.0,
+            message: "Something happened".to_string(),
+            reminder: 0,
+            escalated: false,
         }
+    }
 
-        let mut errors = Vec::new();
-        for (chat_id, message) in messages {
-            if let Err(err) = send_message(&self.api, chat_id, message).await {
-                errors.push(err);
-            }
+    #[test]
+    fn alert_message_without_details_does_not_panic() {
+        for kind in [
+            AlertKind::OutOfRange,
+            AlertKind::BackToNormal,
+            AlertKind::RateExceeded,
+            AlertKind::RateNormal,
+            AlertKind::Offline,
+            AlertKind::Online,
+        ] {
+            let message = alert_message(&event(kind));
+            assert!(message.contains("<code>boiler</code>"), "{message}");
         }
+    }
 
-        errors
+    #[test]
+    fn alert_message_of_rate_event() {
+        let message = alert_message(&AlertEvent {
+            change: Some(-12.5),
+            window: Some(60.0),
+            limit: Some(10.0),
+            ..event(AlertKind::RateExceeded)
+        });
+        assert!(message.starts_with("<b>Alert!</b>\n"), "{message}");
+        assert!(message.contains("value fell by 12.5"), "{message}");
+        assert!(
+            message.ends_with("/ack_boiler to stop reminders"),
+            "{message}"
+        );
     }
 }
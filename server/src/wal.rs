@@ -0,0 +1,386 @@
+use rtherm_common::ProvideRequest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use crate::{batches::BatchKey, config::WalConfig};
+
+/// Sequence number of accepted request.
+pub type Seq = u64;
+
+/// Accepted request with identifier of client that provided it.
+#[derive(Debug, Deserialize)]
+struct Record {
+    /// Missing in records written before clients were identified
+    #[serde(default)]
+    client: Option<String>,
+    #[serde(flatten)]
+    request: ProvideRequest,
+}
+
+/// Borrowed [`Record`] to be written.
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    client: Option<&'a str>,
+    #[serde(flatten)]
+    request: &'a ProvideRequest,
+}
+
+/// Write-ahead log of accepted requests.
+///
+/// Each request is appended and synced to disk before it is acknowledged,
+/// recepients then checkpoint sequence numbers of requests they have processed.
+/// Recepients replay requests after their checkpoints on restart.
+/// Segments that are processed by all recepients are removed.
+///
+/// Log is a directory of segments named after the sequence number of their first record.
+/// Record layout: `len: u32 LE | crc32: u32 LE | payload: [u8; len]`,
+/// where payload is `seq: u64 LE` followed by JSON-encoded [`ProvideRequest`]
+/// with additional `client` field.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    segment_size: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// First sequence numbers of segments in ascending order, the last one is being written
+    segments: Vec<Seq>,
+    file: File,
+    /// Size of the current segment
+    size: u64,
+    /// Sequence number of the next record
+    next_seq: Seq,
+    /// Sequence number of the first request that is not processed by each recepient
+    checkpoints: HashMap<String, Seq>,
+}
+
+impl Wal {
+    const HEADER_LEN: usize = 8;
+    const SEQ_LEN: usize = 8;
+    const SEGMENT_EXT: &'static str = "wal";
+    const CHECKPOINTS_FILE: &'static str = "checkpoints.json";
+
+    /// Open log in directory from `config` or create a new one.
+    pub async fn open(config: &WalConfig) -> Result<Self, io::Error> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir).await?;
+
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == Self::SEGMENT_EXT) {
+                if let Some(seq) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<Seq>().ok())
+                {
+                    segments.push(seq);
+                }
+            }
+        }
+        segments.sort();
+
+        let checkpoints = match fs::read(dir.join(Self::CHECKPOINTS_FILE)).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        // Only the last segment can have a damaged tail.
+        let last = *segments.last().unwrap_or(&0);
+        if segments.is_empty() {
+            segments.push(last);
+        }
+        let path = Self::segment_path(&dir, last);
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let (records, valid_len) = Self::decode(&data);
+        if valid_len < data.len() {
+            log::warn!(
+                "Write-ahead log segment {:?} has {} bytes of damaged tail, discarding them",
+                path,
+                data.len() - valid_len
+            );
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.set_len(valid_len as u64).await?;
+        file.sync_all().await?;
+        sync_dir(&dir).await?;
+
+        let next_seq = records.last().map(|(seq, _)| seq + 1).unwrap_or(last);
+        Ok(Self {
+            dir,
+            segment_size: config.segment_size,
+            state: Mutex::new(State {
+                segments,
+                file,
+                size: valid_len as u64,
+                next_seq,
+                checkpoints,
+            }),
+        })
+    }
+
+    fn segment_path(dir: &Path, first_seq: Seq) -> PathBuf {
+        dir.join(format!("{first_seq:020}.{}", Self::SEGMENT_EXT))
+    }
+
+    /// Decode records from segment data.
+    ///
+    /// Returns decoded records and length of their valid part.
+    fn decode(mut data: &[u8]) -> (Vec<(Seq, Record)>, usize) {
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        while data.len() >= Self::HEADER_LEN {
+            let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+            let payload = match data[Self::HEADER_LEN..].get(..len) {
+                Some(payload) if len >= Self::SEQ_LEN => payload,
+                _ => break,
+            };
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+            let seq = Seq::from_le_bytes(payload[..Self::SEQ_LEN].try_into().unwrap());
+            match serde_json::from_slice::<Record>(&payload[Self::SEQ_LEN..]) {
+                Ok(record) => records.push((seq, record)),
+                Err(e) => {
+                    log::error!("Cannot decode write-ahead log record: {e}");
+                    break;
+                }
+            }
+            data = &data[(Self::HEADER_LEN + len)..];
+            valid_len += Self::HEADER_LEN + len;
+        }
+        (records, valid_len)
+    }
+
+    fn encode(seq: Seq, record: &RecordRef) -> Result<Vec<u8>, io::Error> {
+        let mut payload = seq.to_le_bytes().to_vec();
+        serde_json::to_writer(&mut payload, record)?;
+        let len = u32::try_from(payload.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut record = Vec::with_capacity(Self::HEADER_LEN + payload.len());
+        record.extend(len.to_le_bytes());
+        record.extend(crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        Ok(record)
+    }
+
+    /// Durably append request of `client` to log.
+    ///
+    /// `then` is called with sequence number of request before the next one is appended,
+    /// so that requests are passed on in order of their sequence numbers.
+    pub async fn append(
+        &self,
+        client: Option<&str>,
+        request: &ProvideRequest,
+        then: impl FnOnce(Seq),
+    ) -> Result<Seq, io::Error> {
+        let mut state = self.state.lock().await;
+        if state.size >= self.segment_size {
+            let first_seq = state.next_seq;
+            let path = Self::segment_path(&self.dir, first_seq);
+            state.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            sync_dir(&self.dir).await?;
+            state.segments.push(first_seq);
+            state.size = 0;
+        }
+
+        let seq = state.next_seq;
+        let record = Self::encode(seq, &RecordRef { client, request })?;
+        state.file.write_all(&record).await?;
+        // Error of the background write is reported by flush, not by sync.
+        state.file.flush().await?;
+        state.file.sync_data().await?;
+        state.size += record.len() as u64;
+        state.next_seq += 1;
+        then(seq);
+        Ok(seq)
+    }
+
+    /// Requests that were not processed by recepient `name` yet.
+    ///
+    /// Recepient that has no checkpoint starts from the end of log.
+    pub async fn pending(&self, name: &str) -> Result<Vec<(Seq, ProvideRequest)>, io::Error> {
+        let mut state = self.state.lock().await;
+        let checkpoint = match state.checkpoints.get(name) {
+            Some(seq) => *seq,
+            None => {
+                let seq = state.next_seq;
+                state.checkpoints.insert(name.to_string(), seq);
+                self.save_checkpoints(&state).await?;
+                return Ok(Vec::new());
+            }
+        };
+
+        let mut records = Vec::new();
+        for (i, first_seq) in state.segments.iter().enumerate() {
+            if state
+                .segments
+                .get(i + 1)
+                .is_some_and(|next| *next <= checkpoint)
+            {
+                continue;
+            }
+            let data = fs::read(Self::segment_path(&self.dir, *first_seq)).await?;
+            let (segment, _) = Self::decode(&data);
+            records.extend(
+                segment
+                    .into_iter()
+                    .filter(|(seq, _)| *seq >= checkpoint)
+                    .map(|(seq, record)| (seq, record.request)),
+            );
+        }
+        Ok(records)
+    }
+
+    /// Batch ids of all requests in log.
+    pub async fn batch_ids(&self) -> Result<Vec<BatchKey>, io::Error> {
+        let state = self.state.lock().await;
+        let mut ids = Vec::new();
+        for first_seq in &state.segments {
+            let data = fs::read(Self::segment_path(&self.dir, *first_seq)).await?;
+            let (segment, _) = Self::decode(&data);
+            ids.extend(
+                segment
+                    .into_iter()
+                    .filter_map(|(_, r)| Some((r.client, r.request.batch_id?))),
+            );
+        }
+        Ok(ids)
+    }
+
+    /// Remove checkpoints of recepients that are not used anymore.
+    pub async fn retain_checkpoints<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        let names: Vec<_> = names.into_iter().collect();
+        let mut state = self.state.lock().await;
+        state
+            .checkpoints
+            .retain(|name, _| names.contains(&name.as_str()));
+    }
+
+    /// Record that recepient `name` has processed all requests up to `seq` inclusive.
+    pub async fn checkpoint(&self, name: &str, seq: Seq) -> Result<(), io::Error> {
+        let mut state = self.state.lock().await;
+        state.checkpoints.insert(name.to_string(), seq + 1);
+        self.save_checkpoints(&state).await?;
+
+        // Remove segments that are processed by all recepients.
+        let processed = state.checkpoints.values().copied().min().unwrap_or(0);
+        while state.segments.len() > 1 && state.segments[1] <= processed {
+            let first_seq = state.segments.remove(0);
+            fs::remove_file(Self::segment_path(&self.dir, first_seq)).await?;
+        }
+        Ok(())
+    }
+
+    /// Atomically replace checkpoints file.
+    async fn save_checkpoints(&self, state: &State) -> Result<(), io::Error> {
+        let path = self.dir.join(Self::CHECKPOINTS_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", Self::CHECKPOINTS_FILE));
+        {
+            let mut file = File::create(&tmp).await?;
+            file.write_all(&serde_json::to_vec(&state.checkpoints)?)
+                .await?;
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+        fs::rename(&tmp, &path).await?;
+        sync_dir(&self.dir).await
+    }
+}
+
+/// Make sure that creation or rename of files in directory is persisted.
+async fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    File::open(dir).await?.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtherm_common::{ChannelId, Measurements, Point};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn request(value: f64) -> ProvideRequest {
+        ProvideRequest::new(Measurements::from_iter([(
+            ChannelId::try_from("a").unwrap(),
+            vec![Point {
+                value,
+                time: UNIX_EPOCH + Duration::from_secs(1),
+            }],
+        )]))
+    }
+
+    #[tokio::test]
+    async fn batches_are_keyed_by_client() {
+        let dir = std::env::temp_dir().join(format!("rtherm-wal-clients-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        let config = WalConfig {
+            path: dir.to_str().unwrap().to_string(),
+            segment_size: 1024 * 1024,
+        };
+        let wal = Wal::open(&config).await.unwrap();
+
+        let mut sent = Vec::new();
+        for client in [Some("key:a"), Some("key:b")] {
+            let seq = wal
+                .append(client, &request(1.0), |seq| sent.push(seq))
+                .await
+                .unwrap();
+            assert_eq!(sent.last(), Some(&seq));
+        }
+        assert_eq!(sent, [0, 1]);
+
+        // Record written before clients were identified.
+        let legacy = request(2.0);
+        let mut payload = 2u64.to_le_bytes().to_vec();
+        serde_json::to_writer(&mut payload, &legacy).unwrap();
+        let mut record = (payload.len() as u32).to_le_bytes().to_vec();
+        record.extend(crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(Wal::segment_path(&dir, 0))
+            .await
+            .unwrap();
+        file.write_all(&record).await.unwrap();
+        file.flush().await.unwrap();
+        drop(wal);
+
+        let wal = Wal::open(&config).await.unwrap();
+        let id = request(1.0).batch_id.unwrap();
+        assert_eq!(
+            wal.batch_ids().await.unwrap(),
+            [
+                (Some("key:a".to_string()), id),
+                (Some("key:b".to_string()), id),
+                (None, legacy.batch_id.unwrap()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use reqwest::{header, Client, StatusCode};
+use rtherm_common::ChannelId;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use crate::{
+    alerting::{AlertEvent, Notifier, Rule, Subscription},
+    config::{WebhookConfig, WebhookEndpointConfig},
+    retry::{with_retries, Transient},
+};
+
+/// Whether content type is JSON, e.g. `application/json` or `application/problem+json`.
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json")
+        || essence.to_ascii_lowercase().ends_with("+json")
+}
+
+/// Substitute `{{name}}` placeholders in template with fields of event.
+///
+/// Values are escaped as contents of JSON string if `content_type` is JSON.
+fn render(event: &AlertEvent, template: &str, content_type: &str) -> String {
+    let escape = |value: String| match is_json(content_type) {
+        true => {
+            let quoted = serde_json::to_string(&value).unwrap();
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        false => value,
+    };
+    let opt = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    let (start, end) = match event.normal_range {
+        Some([start, end]) => (Some(start), Some(end)),
+        None => (None, None),
+    };
+    [
+        ("event", event.event.to_string()),
+        ("channel", event.channel.to_string()),
+        ("min", opt(event.min)),
+        ("max", opt(event.max)),
+        ("normal_min", opt(start)),
+        ("normal_max", opt(end)),
+        ("change", opt(event.change)),
+        ("window", opt(event.window)),
+        ("limit", opt(event.limit)),
+        ("time", event.time.to_string()),
+        ("message", event.message.clone()),
+        ("reminder", event.reminder.to_string()),
+        ("escalated", event.escalated.to_string()),
+    ]
+    .into_iter()
+    .fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{{{name}}}}}"), &escape(value))
+    })
+}
+
+#[derive(Debug)]
+pub enum WebhookError {
+    Http(String, reqwest::Error),
+    Status(String, StatusCode),
+    UnknownEndpoint(String),
+}
+
+impl Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(url, e) => write!(f, "Webhook {url} request failed: {e}"),
+            Self::Status(url, status) => write!(f, "Webhook {url} responded with {status}"),
+            Self::UnknownEndpoint(target) => write!(f, "Unknown webhook endpoint {target}"),
+        }
+    }
+}
+
+impl Error for WebhookError {}
+
+impl Transient for WebhookError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(_, e) => e.is_transient(),
+            Self::Status(_, status) => status.is_transient(),
+            Self::UnknownEndpoint(..) => false,
+        }
+    }
+}
+
+struct Endpoint {
+    config: WebhookEndpointConfig,
+    rule: Rule,
+}
+
+impl Endpoint {
+    fn matches(&self, channel: &ChannelId) -> bool {
+        self.config.channels.is_empty()
+            || self
+                .config
+                .channels
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => channel.starts_with(prefix),
+                    None => **channel == **pattern,
+                })
+    }
+}
+
+/// Notifier that posts alert events to webhook endpoints.
+///
+/// Each endpoint is a separate target identified by its index in config.
+pub struct Webhook {
+    client: Client,
+    endpoints: Vec<Endpoint>,
+    max_retries: u32,
+}
+
+impl Webhook {
+    pub fn new(config: WebhookConfig) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs_f64(config.timeout))
+            .build()
+            .map_err(|e| format!("Cannot create HTTP client: {e}"))?;
+        Ok(Self {
+            client,
+            endpoints: config
+                .endpoints
+                .into_iter()
+                .map(|config| Endpoint {
+                    rule: Rule {
+                        normal_range: config.normal_range[0]..=config.normal_range[1],
+                        rate: config.rate.clone(),
+                        debounce: config.debounce.clone(),
+                        remind_interval: config.remind_interval,
+                        escalation: None,
+                    },
+                    config,
+                })
+                .collect(),
+            max_retries: config.max_retries,
+        })
+    }
+
+    async fn send(&self, endpoint: &Endpoint, event: &AlertEvent) -> Result<(), WebhookError> {
+        let config = &endpoint.config;
+        let (content_type, body) = match &config.template {
+            Some(template) => (
+                config.content_type.clone(),
+                render(event, template, &config.content_type),
+            ),
+            None => (
+                "application/json".to_string(),
+                serde_json::to_string(event).unwrap(),
+            ),
+        };
+        let mut request = self
+            .client
+            .post(&config.url)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body);
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| WebhookError::Http(config.url.clone(), e))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(WebhookError::Status(config.url.clone(), status)),
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    type Error = WebhookError;
+
+    async fn subscriptions(&self, channel: ChannelId) -> Vec<Subscription> {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, endpoint)| endpoint.matches(&channel))
+            .map(|(i, endpoint)| Subscription {
+                target: i.to_string(),
+                rule: endpoint.rule.clone(),
+            })
+            .collect()
+    }
+
+    /// Send event retrying transient errors with exponential backoff.
+    async fn notify(&self, target: String, event: AlertEvent) -> Result<(), Self::Error> {
+        let endpoint = target
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| self.endpoints.get(i))
+            .ok_or(WebhookError::UnknownEndpoint(target))?;
+        with_retries(self.max_retries, || self.send(endpoint, &event)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerting::AlertKind;
+    use actix_web::{http, web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// Request received by mock server.
+    struct Request {
+        content_type: Option<String>,
+        authorization: Option<String>,
+        body: String,
+    }
+
+    #[derive(Default)]
+    struct Mock {
+        /// Statuses of responses to the first requests, the rest succeed.
+        statuses: Mutex<Vec<u16>>,
+        requests: Mutex<Vec<Request>>,
+    }
+
+    async fn hook(mock: web::Data<Mock>, request: HttpRequest, body: String) -> HttpResponse {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .map(|value: &http::header::HeaderValue| value.to_str().unwrap().to_string())
+        };
+        mock.requests.lock().unwrap().push(Request {
+            content_type: header(http::header::CONTENT_TYPE),
+            authorization: header(http::header::AUTHORIZATION),
+            body,
+        });
+        let mut statuses = mock.statuses.lock().unwrap();
+        match statuses.is_empty() {
+            true => HttpResponse::Ok().finish(),
+            false => HttpResponse::build(http::StatusCode::from_u16(statuses.remove(0)).unwrap())
+                .body("mock error"),
+        }
+    }
+
+    /// Start mock webhook server, returns its url.
+    fn start(mock: web::Data<Mock>) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .route("/hook", web::post().to(hook))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    fn connect(url: String, template: Option<&str>, max_retries: u32) -> Webhook {
+        Webhook::new(WebhookConfig {
+            max_retries,
+            timeout: 5.0,
+            endpoints: vec![WebhookEndpointConfig {
+                url,
+                channels: Vec::new(),
+                normal_range: [30.0, 80.0],
+                rate: None,
+                debounce: Default::default(),
+                remind_interval: None,
+                template: template.map(str::to_string),
+                content_type: "application/json".to_string(),
+                headers: HashMap::from_iter([(
+                    "Authorization".to_string(),
+                    "Bearer secret".to_string(),
+                )]),
+            }],
+        })
+        .unwrap()
+    }
+
+    fn event() -> AlertEvent {
+        AlertEvent {
+            event: AlertKind::OutOfRange,
+            channel: ChannelId::try_from("a").unwrap(),
+            min: Some(90.5),
+            max: Some(91.0),
+            normal_range: Some([30.0, 80.0]),
+            change: None,
+            window: None,
+            limit: None,
+            time: 1000.0,
+            message: "Value of \"a\" is 91\nabove normal".to_string(),
+            reminder: 0,
+            escalated: false,
+        }
+    }
+
+    #[test]
+    fn render_escapes_json_only() {
+        let template = r#"{"text": "{{event}} {{message}}", "max": {{max}}}"#;
+        let body = render(&event(), template, "application/json; charset=utf-8");
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            value["text"],
+            "out_of_range Value of \"a\" is 91\nabove normal"
+        );
+        assert_eq!(value["max"], 91.0);
+
+        let text = render(&event(), "{{message}}", "text/plain");
+        assert_eq!(text, event().message);
+    }
+
+    #[actix_web::test]
+    async fn posts_rendered_template() {
+        let mock = web::Data::new(Mock::default());
+        let template = r#"{"text": "{{channel}}: {{message}}"}"#;
+        let webhook = connect(start(mock.clone()), Some(template), 0);
+        webhook.notify("0".to_string(), event()).await.unwrap();
+
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].content_type.as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(requests[0].authorization.as_deref(), Some("Bearer secret"));
+        let value: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(value["text"], format!("a: {}", event().message));
+    }
+
+    #[actix_web::test]
+    async fn retries_transient_errors_only() {
+        let mock = web::Data::new(Mock::default());
+        *mock.statuses.lock().unwrap() = vec![500, 429];
+        let webhook = connect(start(mock.clone()), None, 2);
+        webhook.notify("0".to_string(), event()).await.unwrap();
+        {
+            let requests = mock.requests.lock().unwrap();
+            assert_eq!(requests.len(), 3);
+            let sent: AlertEvent = serde_json::from_str(&requests[2].body).unwrap();
+            assert_eq!(sent, event());
+        }
+
+        let mock = web::Data::new(Mock::default());
+        *mock.statuses.lock().unwrap() = vec![404];
+        let webhook = connect(start(mock.clone()), None, 2);
+        let result = webhook.notify("0".to_string(), event()).await;
+        assert!(
+            matches!(result, Err(WebhookError::Status(_, status)) if status == 404),
+            "{result:?}"
+        );
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+    }
+}